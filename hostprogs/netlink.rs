@@ -0,0 +1,220 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! Rust nfqueue host program sample: module `netlink`.
+//!
+//! A minimal `NETLINK_NETFILTER` socket, building and parsing `nfnetlink` messages by hand so that
+//! the sample only depends on the standard library.
+
+use std::io;
+use std::os::raw::{c_int, c_void};
+
+extern "C" {
+    fn socket(domain: c_int, ty: c_int, protocol: c_int) -> c_int;
+    fn bind(fd: c_int, addr: *const c_void, len: u32) -> c_int;
+    fn send(fd: c_int, buf: *const c_void, len: usize, flags: c_int) -> isize;
+    fn recv(fd: c_int, buf: *mut c_void, len: usize, flags: c_int) -> isize;
+    fn close(fd: c_int) -> c_int;
+}
+
+const AF_NETLINK: c_int = 16;
+const SOCK_RAW: c_int = 3;
+const SOCK_CLOEXEC: c_int = 0o2000000;
+const NETLINK_NETFILTER: c_int = 12;
+
+pub(crate) const NLM_F_REQUEST: u16 = 0x1;
+pub(crate) const NLM_F_ACK: u16 = 0x4;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_HDRLEN: usize = 16;
+const NFGENMSG_LEN: usize = 4;
+const NLA_HDRLEN: usize = 4;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+const NFNETLINK_V0: u8 = 0;
+
+/// `struct sockaddr_nl`.
+#[repr(C)]
+struct SockaddrNl {
+    nl_family: u16,
+    nl_pad: u16,
+    nl_pid: u32,
+    nl_groups: u32,
+}
+
+/// Rounds `len` up to the alignment of netlink messages and attributes.
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+/// An `nfnetlink` message being built.
+pub(crate) struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    /// Creates a message of type `ty` addressed to `res_id`, usually a queue number.
+    pub(crate) fn new(ty: u16, flags: u16, family: u8, res_id: u16) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        // The length and sequence number are filled in by `Socket::send`.
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&ty.to_ne_bytes());
+        buf.extend_from_slice(&flags.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&[family, NFNETLINK_V0]);
+        buf.extend_from_slice(&res_id.to_be_bytes());
+        Self { buf }
+    }
+
+    /// Appends an attribute.
+    pub(crate) fn attr(mut self, ty: u16, payload: &[u8]) -> Self {
+        let len = NLA_HDRLEN + payload.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(payload);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+}
+
+/// A received `nfnetlink` message, other than acknowledgements.
+pub(crate) struct Received<'a> {
+    pub(crate) ty: u16,
+    pub(crate) res_id: u16,
+    attrs: &'a [u8],
+}
+
+impl<'a> Received<'a> {
+    /// Returns the payload of the first attribute of type `ty`.
+    pub(crate) fn attr(&self, ty: u16) -> Option<&'a [u8]> {
+        let mut attrs = self.attrs;
+        while attrs.len() >= NLA_HDRLEN {
+            let len = usize::from(u16_at(attrs, 0));
+            if len < NLA_HDRLEN || len > attrs.len() {
+                break;
+            }
+            if u16_at(attrs, 2) & NLA_TYPE_MASK == ty {
+                return Some(&attrs[NLA_HDRLEN..len]);
+            }
+            attrs = &attrs[align(len).min(attrs.len())..];
+        }
+        None
+    }
+}
+
+/// A `NETLINK_NETFILTER` socket, closed when dropped.
+pub(crate) struct Socket {
+    fd: c_int,
+    seq: u32,
+}
+
+impl Socket {
+    pub(crate) fn open() -> io::Result<Self> {
+        // SAFETY: FFI call with no additional requirements.
+        let fd = unsafe { socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_NETFILTER) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let this = Self { fd, seq: 0 };
+
+        // The kernel assigns the port id.
+        let addr = SockaddrNl {
+            nl_family: AF_NETLINK as u16,
+            nl_pad: 0,
+            nl_pid: 0,
+            nl_groups: 0,
+        };
+        // SAFETY: `addr` is a valid `sockaddr_nl` of the given length.
+        let ret = unsafe {
+            bind(
+                fd,
+                &addr as *const SockaddrNl as *const c_void,
+                std::mem::size_of::<SockaddrNl>() as u32,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(this)
+    }
+
+    /// Sends `message` to the kernel.
+    pub(crate) fn send(&mut self, mut message: Message) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let len = message.buf.len() as u32;
+        message.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        message.buf[8..12].copy_from_slice(&self.seq.to_ne_bytes());
+
+        // SAFETY: The buffer is valid for reads of its length.
+        let ret = unsafe {
+            send(
+                self.fd,
+                message.buf.as_ptr() as *const c_void,
+                message.buf.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Receives a datagram into `buf` and returns the messages in it.
+    ///
+    /// Acknowledgements are skipped, and errors reported by the kernel are returned.
+    pub(crate) fn recv<'a>(&mut self, buf: &'a mut [u8]) -> io::Result<Vec<Received<'a>>> {
+        // SAFETY: The buffer is valid for writes of its length.
+        let ret = unsafe { recv(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len(), 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut data = &buf[..ret as usize];
+        let mut messages = Vec::new();
+        while data.len() >= NLMSG_HDRLEN {
+            let len = u32_at(data, 0) as usize;
+            if len < NLMSG_HDRLEN || len > data.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated netlink message",
+                ));
+            }
+            let ty = u16_at(data, 4);
+            let payload = &data[NLMSG_HDRLEN..len];
+            if ty == NLMSG_ERROR {
+                // `struct nlmsgerr` starts with the negated errno, or zero for acknowledgements.
+                let error = payload.get(..4).map_or(0, |_| u32_at(payload, 0) as i32);
+                if error != 0 {
+                    return Err(io::Error::from_raw_os_error(-error));
+                }
+            } else if payload.len() >= NFGENMSG_LEN {
+                messages.push(Received {
+                    ty,
+                    res_id: u16::from_be_bytes([payload[2], payload[3]]),
+                    attrs: &payload[NFGENMSG_LEN..],
+                });
+            }
+            data = &data[align(len).min(data.len())..];
+        }
+        Ok(messages)
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        // SAFETY: `fd` is owned by the socket.
+        unsafe { close(self.fd) };
+    }
+}
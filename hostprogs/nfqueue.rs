@@ -0,0 +1,227 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! Rust nfqueue host program sample.
+//!
+//! Issues verdicts for the packets sent to an NFQUEUE, e.g., by the `queue` rules of the
+//! `rust_netfilter` sample: TCP and UDP packets to one of the destination ports given with `-d`
+//! are dropped, and all other packets are accepted.
+//!
+//! Usage: `nfqueue [-q NUM] [-d PORT]...`
+
+mod netlink;
+
+use netlink::{Message, Received, Socket, NLM_F_ACK, NLM_F_REQUEST};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::process;
+
+const NFNL_SUBSYS_QUEUE: u16 = 3;
+const NFQNL_MSG_PACKET: u16 = 0;
+const NFQNL_MSG_VERDICT: u16 = 1;
+const NFQNL_MSG_CONFIG: u16 = 2;
+
+const NFQA_PACKET_HDR: u16 = 1;
+const NFQA_VERDICT_HDR: u16 = 2;
+const NFQA_PAYLOAD: u16 = 10;
+const NFQA_CFG_CMD: u16 = 1;
+const NFQA_CFG_PARAMS: u16 = 2;
+
+const NFQNL_CFG_CMD_BIND: u8 = 1;
+const NFQNL_COPY_PACKET: u8 = 2;
+
+const NF_DROP: u32 = 0;
+const NF_ACCEPT: u32 = 1;
+
+const AF_UNSPEC: u8 = 0;
+const ENOBUFS: i32 = 105;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+/// Number of bytes of each packet copied to userspace, enough for the IP and transport headers.
+const COPY_RANGE: u32 = 128;
+
+const USAGE: &str = "Usage: nfqueue [-q NUM] [-d PORT]...";
+
+struct Options {
+    queue: u16,
+    drop_ports: Vec<u16>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        queue: 0,
+        drop_ports: Vec::new(),
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", arg))?;
+        let value = value
+            .parse()
+            .map_err(|_| format!("Invalid value for {}: {}", arg, value))?;
+        match arg.as_str() {
+            "-q" => options.queue = value,
+            "-d" => options.drop_ports.push(value),
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+fn message_type(msg: u16) -> u16 {
+    NFNL_SUBSYS_QUEUE << 8 | msg
+}
+
+/// The headers of a queued packet relevant to the policy.
+struct Summary {
+    src: String,
+    dst: String,
+    protocol: u8,
+    dst_port: Option<u16>,
+}
+
+impl Summary {
+    /// Decodes the IP header, and the ports if the transport header follows it directly.
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let (src, dst, protocol, offset) = match packet.first()? >> 4 {
+            4 if packet.len() >= 20 => {
+                let src: [u8; 4] = packet[12..16].try_into().ok()?;
+                let dst: [u8; 4] = packet[16..20].try_into().ok()?;
+                (
+                    Ipv4Addr::from(src).to_string(),
+                    Ipv4Addr::from(dst).to_string(),
+                    packet[9],
+                    usize::from(packet[0] & 0xf) * 4,
+                )
+            }
+            6 if packet.len() >= 40 => {
+                let src: [u8; 16] = packet[8..24].try_into().ok()?;
+                let dst: [u8; 16] = packet[24..40].try_into().ok()?;
+                (
+                    Ipv6Addr::from(src).to_string(),
+                    Ipv6Addr::from(dst).to_string(),
+                    packet[6],
+                    40,
+                )
+            }
+            _ => return None,
+        };
+        let dst_port = match protocol {
+            IPPROTO_TCP | IPPROTO_UDP => packet
+                .get(offset + 2..offset + 4)
+                .map(|port| u16::from_be_bytes([port[0], port[1]])),
+            _ => None,
+        };
+        Some(Self {
+            src,
+            dst,
+            protocol,
+            dst_port,
+        })
+    }
+}
+
+/// Returns the verdict for a queued packet and logs it.
+fn decide(options: &Options, id: u32, payload: &[u8]) -> u32 {
+    let summary = match Summary::parse(payload) {
+        Some(summary) => summary,
+        None => {
+            println!("#{}: not an IP packet, accept", id);
+            return NF_ACCEPT;
+        }
+    };
+    let (verdict, name) = match summary.dst_port {
+        Some(port) if options.drop_ports.contains(&port) => (NF_DROP, "drop"),
+        _ => (NF_ACCEPT, "accept"),
+    };
+    match summary.dst_port {
+        Some(port) => println!(
+            "#{}: {} -> {} proto {} dport {}, {}",
+            id, summary.src, summary.dst, summary.protocol, port, name
+        ),
+        None => println!(
+            "#{}: {} -> {} proto {}, {}",
+            id, summary.src, summary.dst, summary.protocol, name
+        ),
+    }
+    verdict
+}
+
+fn handle(socket: &mut Socket, options: &Options, message: &Received<'_>) -> io::Result<()> {
+    if message.ty != message_type(NFQNL_MSG_PACKET) {
+        return Ok(());
+    }
+    let id = match message.attr(NFQA_PACKET_HDR) {
+        Some(hdr) if hdr.len() >= 4 => u32::from_be_bytes([hdr[0], hdr[1], hdr[2], hdr[3]]),
+        _ => return Ok(()),
+    };
+    let verdict = decide(options, id, message.attr(NFQA_PAYLOAD).unwrap_or(&[]));
+
+    let mut hdr = [0; 8];
+    hdr[..4].copy_from_slice(&verdict.to_be_bytes());
+    hdr[4..].copy_from_slice(&id.to_be_bytes());
+    socket.send(
+        Message::new(
+            message_type(NFQNL_MSG_VERDICT),
+            NLM_F_REQUEST,
+            AF_UNSPEC,
+            message.res_id,
+        )
+        .attr(NFQA_VERDICT_HDR, &hdr),
+    )
+}
+
+fn run(options: &Options) -> io::Result<()> {
+    let mut socket = Socket::open()?;
+
+    // `struct nfqnl_msg_config_cmd`: the command, padding and a family ignored by binds.
+    let cmd = [NFQNL_CFG_CMD_BIND, 0, 0, 0];
+    // `struct nfqnl_msg_config_params`, which is packed.
+    let mut params = [0; 5];
+    params[..4].copy_from_slice(&COPY_RANGE.to_be_bytes());
+    params[4] = NFQNL_COPY_PACKET;
+    socket.send(
+        Message::new(
+            message_type(NFQNL_MSG_CONFIG),
+            NLM_F_REQUEST | NLM_F_ACK,
+            AF_UNSPEC,
+            options.queue,
+        )
+        .attr(NFQA_CFG_CMD, &cmd)
+        .attr(NFQA_CFG_PARAMS, &params),
+    )?;
+    println!("Listening on queue {}", options.queue);
+
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let messages = match socket.recv(&mut buf) {
+            Ok(messages) => messages,
+            // Packets were queued faster than they are read, and the kernel dropped the ones that
+            // did not fit in the socket buffer.
+            Err(e) if e.raw_os_error() == Some(ENOBUFS) => {
+                eprintln!("nfqueue: receive buffer overrun, packets were lost");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        for message in &messages {
+            handle(&mut socket, options, message)?;
+        }
+    }
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("nfqueue: {}", e);
+        process::exit(1);
+    }
+}
@@ -1,60 +1,1575 @@
 // SPDX-License-Identifier: GPL-2.0
 
 //! Rust echo server sample.
+//!
+//! When the module is unloaded, the server stops accepting new connections, closes the ones that
+//! are idle and gives the ones in the middle of a write `shutdown_grace_ms` milliseconds to
+//! complete. Connections still open after that are aborted.
+//!
+//! At most `max_connections` clients are served at a time. Extra clients are left waiting in the
+//! listen backlog until a connection closes or, if `reject_excess` is set, accepted and closed
+//! straight away. Failures to accept or to spawn a task are retried with exponential backoff and
+//! logged at a limited rate.
+//!
+//! A connection is closed when it waits for more than `idle_timeout_ms` milliseconds for a single
+//! read or write, or once it has been open for `max_lifetime_ms` milliseconds.
+//!
+//! Input is split into lines and handed to the handler selected by the `protocol` parameter:
+//!
+//! * `echo` sends every line back,
+//! * `discard` drops all input,
+//! * `chargen` sends a stream of rotating ASCII characters, as in RFC 864,
+//! * `daytime` sends the current UTC time and closes the connection, as in RFC 867,
+//! * `upper` sends every line back converted to upper case,
+//! * `kv` serves an in-memory key-value store shared by all connections, speaking the `get`,
+//!   `set`, `delete`, `stats` and `quit` commands of the memcached text protocol, so it can be
+//!   load-tested over loopback with memcached clients and benchmarks. The store holds up to
+//!   `kv_max_bytes` bytes of keys, values and per-item overhead. Expired items are dropped when
+//!   they are next accessed, or all at once when the store is full. Items have no CAS unique, so
+//!   `gets` is answered with `ERROR` like any other unsupported command,
+//! * `proxy` forwards each connection to the `upstream` IPv4 address and port, copying data in
+//!   both directions until either side closes its end.
+//!
+//! Each direction goes through a heap buffer of `buffer_size` bytes, which also bounds the length
+//! of lines. A chunk is only read once the previous one has been written out, so a slow reader
+//! makes the server stop reading from the other side, and TCP flow control pushes back on it.
+//!
+//! `/proc/net/rust_echo_server` lists the open connections, with their peer address, the number of
+//! bytes received and sent and their age, along with global connection counters.
+//!
+//! The executor runs on the workqueue selected by the `workqueue` parameter: one of the shared
+//! `system`, `system_highpri`, `system_unbound` or `system_long` queues, or a `dedicated` one
+//! created for the server so that echo traffic is isolated from other work items. The dedicated
+//! queue is unbound if `wq_unbound` is set, high priority if `wq_highpri` is set, and is listed
+//! under `/sys/devices/virtual/workqueue/rust_echo_server`, where the CPU affinity and nice level
+//! of unbound queues can be changed.
+//!
+//! Accepted connections get `TCP_NODELAY` if `tcp_nodelay` is set, `SO_KEEPALIVE` if `keepalive`
+//! is set (with the probe timing given by `keepalive_idle_s`, `keepalive_interval_s` and
+//! `keepalive_count`), and the send and receive buffer sizes given by `sndbuf` and `rcvbuf`.
+//! Parameters left at 0 keep the kernel defaults.
 
+use core::ffi::{c_int, c_ulong, c_void};
+use core::fmt::{self, Write};
+use core::future::{self, Future};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
 use kernel::{
-    kasync::executor::{workqueue::Executor as WqExecutor, AutoStopHandle, Executor},
+    bindings, c_str,
+    delay::coarse_sleep,
+    kasync::executor::{workqueue::Executor as WqExecutor, AutoStopHandle, Executor, Task},
     kasync::net::{TcpListener, TcpStream},
+    kasync::time::{sleep, Sleep},
+    mutex_init,
     net::{self, Ipv4Addr, SocketAddr, SocketAddrV4},
     prelude::*,
-    spawn_task,
-    sync::{Arc, ArcBorrow},
+    spawn_task, spinlock_init,
+    str::CString,
+    sync::{Arc, ArcBorrow, Mutex, SpinLock, UniqueArc},
+    workqueue::Queue,
+    Opaque,
 };
 
-async fn echo_server(stream: TcpStream) -> Result {
-    let mut buf = [0u8; 1024];
+/// How often the number of remaining connections is checked while draining.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Returns the current monotonic time in nanoseconds.
+fn now() -> i64 {
+    // SAFETY: FFI call with no additional requirements.
+    unsafe { bindings::ktime_get() }
+}
+
+/// Converts `duration` to jiffies, saturating at the largest delay supported by the kernel.
+fn to_jiffies(duration: Duration) -> c_ulong {
+    let ms = duration.as_millis().try_into().unwrap_or(u32::MAX);
+    // SAFETY: FFI call with no additional requirements.
+    unsafe { bindings::__msecs_to_jiffies(ms) }
+}
+
+/// Exponential backoff between retries of an operation that keeps failing.
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    const MIN_DELAY: Duration = Duration::from_millis(10);
+    const MAX_DELAY: Duration = Duration::from_secs(5);
+
+    fn new() -> Self {
+        Self {
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Resets the delay after the operation succeeds.
+    fn reset(&mut self) {
+        self.delay = Duration::ZERO;
+    }
+
+    /// Waits before the next retry, doubling the delay every time.
+    async fn wait(&mut self) {
+        self.delay = (self.delay * 2).clamp(Self::MIN_DELAY, Self::MAX_DELAY);
+        sleep(self.delay).await;
+    }
+}
+
+/// A connection being served by a [`serve`] task.
+struct Connection {
+    /// The address of the client, if known.
+    peer: Option<SocketAddr>,
+
+    /// The time at which the connection was accepted, in `ktime_get` nanoseconds.
+    started: i64,
+
+    /// The number of bytes received from the client.
+    bytes_in: AtomicU64,
+
+    /// The number of bytes sent to the client.
+    bytes_out: AtomicU64,
+
+    /// Set when the connection must be closed the next time it waits for input.
+    closing: AtomicBool,
+
+    /// The waker of the task serving the connection, while it waits for input.
+    waker: SpinLock<Option<Waker>>,
+
+    /// The longest a single read or write may wait.
+    idle_timeout: Option<Duration>,
+
+    /// The time at which the connection must be closed, in `ktime_get` nanoseconds.
+    deadline: Option<i64>,
+}
+
+impl Connection {
+    fn try_new(peer: Option<SocketAddr>) -> Result<Arc<Self>> {
+        let started = now();
+        let timeout = |ms: u32| (ms != 0).then(|| Duration::from_millis(ms.into()));
+        let idle_timeout = timeout(*idle_timeout_ms.read());
+        let deadline =
+            timeout(*max_lifetime_ms.read()).map(|t| started.saturating_add(t.as_nanos() as i64));
+
+        let mut conn = Pin::from(UniqueArc::try_new(Self {
+            peer,
+            started,
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            closing: AtomicBool::new(false),
+            // SAFETY: `spinlock_init!` is called below.
+            waker: unsafe { SpinLock::new(None) },
+            idle_timeout,
+            deadline,
+        })?);
+
+        // SAFETY: `waker` is pinned when `conn` is.
+        let pinned = unsafe { conn.as_mut().map_unchecked_mut(|c| &mut c.waker) };
+        spinlock_init!(pinned, "Connection::waker");
+
+        Ok(conn.into())
+    }
+
+    /// Asks the task serving the connection to close it once it next waits for input.
+    fn close(&self) {
+        self.closing.store(true, Ordering::Relaxed);
+        let waker = self.waker.lock_irqdisable().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Returns whether the connection has been asked to close.
+    fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Relaxed)
+    }
+
+    /// Waits for input with `fut`.
+    ///
+    /// Gives up with `ESHUTDOWN` if the connection is closed in the meantime, and with `ETIMEDOUT`
+    /// if it times out.
+    fn input<F>(&self, fut: F) -> Guarded<'_, F> {
+        self.guard(fut, true)
+    }
+
+    /// Waits for output with `fut`, giving up with `ETIMEDOUT` if the connection times out.
+    fn output<F>(&self, fut: F) -> Guarded<'_, F> {
+        self.guard(fut, false)
+    }
+
+    fn guard<F>(&self, fut: F, interruptible: bool) -> Guarded<'_, F> {
+        let remaining = self
+            .deadline
+            .map(|deadline| Duration::from_nanos(deadline.saturating_sub(now()).max(0) as u64));
+        let timeout = match (self.idle_timeout, remaining) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        Guarded {
+            conn: self,
+            interruptible,
+            timeout: timeout.map(sleep),
+            fut,
+        }
+    }
+}
+
+/// A future that completes early when its connection is closed or times out.
+///
+/// Created by [`Connection::input`] and [`Connection::output`].
+struct Guarded<'a, F> {
+    conn: &'a Connection,
+    interruptible: bool,
+    timeout: Option<Sleep>,
+    fut: F,
+}
+
+impl<F: Future<Output = Result<T>>, T> Future for Guarded<'_, F> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T>> {
+        // SAFETY: `timeout` and `fut` are structurally pinned and never moved out of `self`.
+        let this = unsafe { self.get_unchecked_mut() };
+
+        if this.interruptible {
+            if this.conn.closing.load(Ordering::Relaxed) {
+                return Poll::Ready(Err(ESHUTDOWN));
+            }
+            *this.conn.waker.lock_irqdisable() = Some(cx.waker().clone());
+
+            // Check again now that the waker is registered so that a concurrent call to `close`
+            // is never missed.
+            if this.conn.closing.load(Ordering::Relaxed) {
+                return Poll::Ready(Err(ESHUTDOWN));
+            }
+        }
+
+        if let Some(timeout) = &mut this.timeout {
+            // SAFETY: `timeout` is structurally pinned, see above.
+            if unsafe { Pin::new_unchecked(timeout) }.poll(cx).is_ready() {
+                return Poll::Ready(Err(ETIMEDOUT));
+            }
+        }
+
+        // SAFETY: `fut` is structurally pinned, see above.
+        unsafe { Pin::new_unchecked(&mut this.fut) }.poll(cx)
+    }
+}
+
+/// The protocol spoken on accepted connections.
+#[derive(Clone, Copy)]
+enum Protocol {
+    Echo,
+    Discard,
+    Chargen,
+    Daytime,
+    Upper,
+    Kv,
+    Proxy,
+}
+
+impl Protocol {
+    fn from_name(name: &[u8]) -> Result<Self> {
+        Ok(match name {
+            b"echo" => Self::Echo,
+            b"discard" => Self::Discard,
+            b"chargen" => Self::Chargen,
+            b"daytime" => Self::Daytime,
+            b"upper" => Self::Upper,
+            b"kv" => Self::Kv,
+            b"proxy" => Self::Proxy,
+            _ => {
+                pr_err!("Unknown protocol: {}\n", core::str::from_utf8(name)?);
+                return Err(EINVAL);
+            }
+        })
+    }
+}
+
+/// The number of hash buckets in a [`KvStore`].
+const KV_BUCKETS: usize = 1024;
+
+/// The longest key accepted by a [`KvStore`], as in memcached.
+const KV_MAX_KEY_LEN: usize = 250;
+
+/// The largest value accepted by a [`KvStore`].
+const KV_MAX_VALUE_LEN: usize = 64 * 1024;
+
+/// The largest value that is read and thrown away after being rejected. The connection is closed
+/// instead for larger ones, since skipping them would take too long.
+const KV_MAX_SKIPPED_LEN: usize = 1 << 20;
+
+/// Expiry times up to this many seconds are relative to the current time, as in memcached.
+const KV_MAX_RELATIVE_EXPIRY: i64 = 30 * 24 * 60 * 60;
+
+/// An item of a [`KvStore`].
+struct KvEntry {
+    key: Vec<u8>,
+    flags: u32,
+    /// The wall clock time, in seconds, after which the entry is gone, or 0 if it never expires.
+    expires: i64,
+    value: Vec<u8>,
+}
+
+impl KvEntry {
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+
+    /// Returns the memory charged for the entry, including its bookkeeping so that many small
+    /// items cannot get around the limit of the store.
+    fn size(&self) -> usize {
+        core::mem::size_of::<Self>() + self.key.len() + self.value.len()
+    }
+}
+
+/// The hash table backing the `kv` protocol.
+struct KvStore {
+    /// Empty until the first item is stored.
+    buckets: Vec<Vec<KvEntry>>,
+    items: usize,
+    bytes: usize,
+    cmd_get: u64,
+    get_hits: u64,
+    get_misses: u64,
+    cmd_set: u64,
+    delete_hits: u64,
+    delete_misses: u64,
+}
+
+impl KvStore {
+    fn new() -> Self {
+        Self {
+            buckets: Vec::new(),
+            items: 0,
+            bytes: 0,
+            cmd_get: 0,
+            get_hits: 0,
+            get_misses: 0,
+            cmd_set: 0,
+            delete_hits: 0,
+            delete_misses: 0,
+        }
+    }
+
+    /// Returns the index of the bucket for `key`, using the FNV-1a hash.
+    fn bucket_index(key: &[u8]) -> usize {
+        let hash = key.iter().fold(0xcbf29ce484222325u64, |h, &b| {
+            (h ^ u64::from(b)).wrapping_mul(0x100000001b3)
+        });
+        (hash % KV_BUCKETS as u64) as usize
+    }
+
+    /// Removes the entry for `key` from its bucket, if there is one.
+    fn remove(&mut self, key: &[u8]) -> Option<KvEntry> {
+        let bucket = self.buckets.get_mut(Self::bucket_index(key))?;
+        let i = bucket.iter().position(|e| e.key == key)?;
+        let entry = bucket.swap_remove(i);
+        self.items -= 1;
+        self.bytes -= entry.size();
+        Some(entry)
+    }
+
+    /// Drops all the expired entries.
+    fn sweep(&mut self, now: i64) {
+        for bucket in &mut self.buckets {
+            bucket.retain(|e| {
+                if !e.is_expired(now) {
+                    return true;
+                }
+                self.items -= 1;
+                self.bytes -= e.size();
+                false
+            });
+        }
+    }
+
+    /// Returns the flags and a copy of the value stored for `key`.
+    fn get(&mut self, key: &[u8], now: i64) -> Result<Option<(u32, Vec<u8>)>> {
+        self.cmd_get += 1;
+        let found = self
+            .buckets
+            .get(Self::bucket_index(key))
+            .and_then(|b| b.iter().find(|e| e.key == key));
+        let entry = match found {
+            Some(e) if !e.is_expired(now) => e,
+            Some(_) => {
+                self.remove(key);
+                self.get_misses += 1;
+                return Ok(None);
+            }
+            None => {
+                self.get_misses += 1;
+                return Ok(None);
+            }
+        };
+
+        let mut value = Vec::try_with_capacity(entry.value.len())?;
+        value.try_extend_from_slice(&entry.value)?;
+        let flags = entry.flags;
+        self.get_hits += 1;
+        Ok(Some((flags, value)))
+    }
+
+    /// Stores `entry`, replacing any previous entry with the same key.
+    ///
+    /// Fails with `ENOSPC` if that would take the store over `max_bytes`, even once the expired
+    /// entries are dropped.
+    fn set(&mut self, entry: KvEntry, max_bytes: usize, now: i64) -> Result {
+        self.cmd_set += 1;
+        if self.buckets.is_empty() {
+            self.buckets.try_reserve_exact(KV_BUCKETS)?;
+            for _ in 0..KV_BUCKETS {
+                self.buckets.try_push(Vec::new())?;
+            }
+        }
+
+        let index = Self::bucket_index(&entry.key);
+        let fits = |store: &Self| {
+            let old_size = store.buckets[index]
+                .iter()
+                .find(|e| e.key == entry.key)
+                .map_or(0, KvEntry::size);
+            store.bytes - old_size + entry.size() <= max_bytes
+        };
+        if !fits(self) {
+            self.sweep(now);
+            if !fits(self) {
+                return Err(ENOSPC);
+            }
+        }
+
+        // Make room first so that a failed allocation leaves the old entry in place.
+        self.buckets[index].try_reserve(1)?;
+        self.remove(&entry.key);
+        self.items += 1;
+        self.bytes += entry.size();
+        self.buckets[index].try_push(entry)?;
+        Ok(())
+    }
+
+    /// Removes the entry for `key`, returning whether there was a live one.
+    fn delete(&mut self, key: &[u8], now: i64) -> bool {
+        let found = matches!(self.remove(key), Some(e) if !e.is_expired(now));
+        if found {
+            self.delete_hits += 1;
+        } else {
+            self.delete_misses += 1;
+        }
+        found
+    }
+}
+
+/// The smallest buffer accepted for the `buffer_size` parameter.
+const MIN_BUFFER_SIZE: usize = 64;
+
+/// The largest buffer accepted for the `buffer_size` parameter.
+const MAX_BUFFER_SIZE: usize = 1 << 20;
+
+/// Allocates a zeroed I/O buffer of `size` bytes.
+fn alloc_buffer(size: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::try_with_capacity(size)?;
+    buf.try_resize(size, 0)?;
+    Ok(buf)
+}
+
+/// Parses an IPv4 socket address of the form `a.b.c.d:port`.
+fn parse_ipv4_addr(s: &[u8]) -> Result<SocketAddr> {
+    let s = core::str::from_utf8(s)?;
+    let (ip, port) = s.split_once(':').ok_or(EINVAL)?;
+    let mut octets = [0u8; 4];
+    let mut parts = ip.split('.');
+    for octet in &mut octets {
+        *octet = parts.next().ok_or(EINVAL)?.parse().map_err(|_| EINVAL)?;
+    }
+    if parts.next().is_some() {
+        return Err(EINVAL);
+    }
+    let port = port.parse().map_err(|_| EINVAL)?;
+    let [a, b, c, d] = octets;
+    Ok(SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::new(a, b, c, d),
+        port,
+    )))
+}
+
+/// State shared by the accept loop and all connections.
+struct Server {
+    /// The protocol spoken on accepted connections.
+    protocol: Protocol,
+
+    /// The size of the I/O buffers of each connection.
+    buffer_size: usize,
+
+    /// The address connections are forwarded to by the `proxy` protocol.
+    upstream: Option<SocketAddr>,
+
+    /// The connections currently being served.
+    connections: Mutex<Vec<Arc<Connection>>>,
+
+    /// The store served by the `kv` protocol.
+    kv: Mutex<KvStore>,
+
+    /// The monotonic time, in seconds, at which the server started.
+    started: i64,
+
+    /// The number of connections accepted and served.
+    accepted: AtomicU64,
+
+    /// The number of connections closed straight away because of the connection limit.
+    refused: AtomicU64,
+
+    /// The number of failures to accept or serve a connection.
+    errored: AtomicU64,
+
+    /// The number of connections closed because they timed out.
+    timed_out: AtomicU64,
+
+    /// The waker of the accept loop, while it waits for a connection slot to free up.
+    slot_waker: SpinLock<Option<Waker>>,
+
+    /// Limits the messages logged by the accept loop.
+    log_ratelimit: Opaque<bindings::ratelimit_state>,
+}
+
+// SAFETY: `log_ratelimit` is only used through `___ratelimit`, which serialises callers with the
+// lock embedded in it. The other fields are `Send` and `Sync`.
+unsafe impl Send for Server {}
+
+// SAFETY: See `Send` above.
+unsafe impl Sync for Server {}
+
+impl Server {
+    const LOG_INTERVAL: Duration = Duration::from_secs(5);
+    const LOG_BURST: i32 = 10;
+
+    fn try_new(
+        proto: Protocol,
+        size: usize,
+        upstream_addr: Option<SocketAddr>,
+    ) -> Result<Arc<Self>> {
+        let mut server = Pin::from(UniqueArc::try_new(Self {
+            protocol: proto,
+            buffer_size: size,
+            upstream: upstream_addr,
+            // SAFETY: `mutex_init!` is called below.
+            connections: unsafe { Mutex::new(Vec::new()) },
+            // SAFETY: `mutex_init!` is called below.
+            kv: unsafe { Mutex::new(KvStore::new()) },
+            // SAFETY: FFI call with no additional requirements.
+            started: unsafe { bindings::ktime_get_seconds() },
+            accepted: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+            errored: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+            // SAFETY: `spinlock_init!` is called below.
+            slot_waker: unsafe { SpinLock::new(None) },
+            log_ratelimit: Opaque::uninit(),
+        })?);
+
+        // SAFETY: `connections` is pinned when `server` is.
+        let pinned = unsafe { server.as_mut().map_unchecked_mut(|s| &mut s.connections) };
+        mutex_init!(pinned, "Server::connections");
+
+        // SAFETY: `kv` is pinned when `server` is.
+        let pinned = unsafe { server.as_mut().map_unchecked_mut(|s| &mut s.kv) };
+        mutex_init!(pinned, "Server::kv");
+
+        // SAFETY: `slot_waker` is pinned when `server` is.
+        let pinned = unsafe { server.as_mut().map_unchecked_mut(|s| &mut s.slot_waker) };
+        spinlock_init!(pinned, "Server::slot_waker");
+
+        // SAFETY: `log_ratelimit` is pinned when `server` is, and is initialised before use.
+        unsafe {
+            bindings::ratelimit_state_init(
+                server.log_ratelimit.get(),
+                to_jiffies(Self::LOG_INTERVAL) as _,
+                Self::LOG_BURST,
+            )
+        };
+
+        Ok(server.into())
+    }
+
+    /// Returns whether the accept loop may log a message now, like `printk_ratelimit`.
+    ///
+    /// At most `LOG_BURST` messages are logged per `LOG_INTERVAL`, and the number of suppressed
+    /// ones is reported once the interval is over.
+    fn log_allowed(&self) -> bool {
+        // SAFETY: `log_ratelimit` was initialised in `try_new`.
+        unsafe {
+            bindings::___ratelimit(
+                self.log_ratelimit.get(),
+                c_str!("rust_echo_server").as_char_ptr(),
+            ) != 0
+        }
+    }
+
+    /// Returns the number of connections currently being served.
+    fn active(&self) -> usize {
+        self.connections.lock().len()
+    }
+
+    /// Waits until fewer than `max` connections are being served.
+    fn wait_for_slot(&self, max: usize) -> impl Future<Output = ()> + '_ {
+        future::poll_fn(move |cx| {
+            if self.active() < max {
+                return Poll::Ready(());
+            }
+            *self.slot_waker.lock_irqdisable() = Some(cx.waker().clone());
+
+            // Check again now that the waker is registered so that a closing connection is never
+            // missed.
+            if self.active() < max {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+    }
+
+    /// Registers a new connection, which is unregistered when the returned handle is dropped.
+    fn register(server: &Arc<Self>, peer: Option<SocketAddr>) -> Result<ConnectionHandle> {
+        let conn = Connection::try_new(peer)?;
+        server.connections.lock().try_push(conn.clone())?;
+        Ok(ConnectionHandle {
+            server: server.clone(),
+            conn,
+        })
+    }
+
+    /// Closes idle connections and waits up to `grace` for the busy ones to complete.
+    ///
+    /// Returns the number of connections that are still open at the end of the grace period.
+    fn drain(&self, grace: Duration) -> usize {
+        for conn in self.connections.lock().iter() {
+            conn.close();
+        }
+
+        let mut waited = Duration::ZERO;
+        loop {
+            let remaining = self.active();
+            if remaining == 0 || waited >= grace {
+                return remaining;
+            }
+            coarse_sleep(DRAIN_POLL_INTERVAL);
+            waited += DRAIN_POLL_INTERVAL;
+        }
+    }
+}
+
+/// A registered connection.
+///
+/// It is owned by the task serving the connection, so it is unregistered both when the task
+/// completes and when the executor drops the task on shutdown.
+struct ConnectionHandle {
+    server: Arc<Server>,
+    conn: Arc<Connection>,
+}
+
+impl core::ops::Deref for ConnectionHandle {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        {
+            let mut connections = self.server.connections.lock();
+            if let Some(i) = connections
+                .iter()
+                .position(|c| core::ptr::eq(&**c, &*self.conn))
+            {
+                connections.swap_remove(i);
+            }
+        }
+
+        let waker = self.server.slot_waker.lock_irqdisable().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Splits the input of a connection into lines.
+struct LineReader<'a> {
+    conn: &'a Connection,
+    stream: &'a TcpStream,
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl<'a> LineReader<'a> {
+    fn try_new(conn: &'a Connection, stream: &'a TcpStream, size: usize) -> Result<Self> {
+        Ok(Self {
+            conn,
+            stream,
+            buf: alloc_buffer(size)?,
+            start: 0,
+            end: 0,
+        })
+    }
+
+    /// Returns the next line, including its `\n` terminator, or `None` at the end of the input.
+    ///
+    /// Lines longer than the buffer are returned in chunks, and the last line may not be
+    /// terminated.
+    async fn next(&mut self) -> Result<Option<&mut [u8]>> {
+        loop {
+            if let Some(i) = self.buf[self.start..self.end]
+                .iter()
+                .position(|&b| b == b'\n')
+            {
+                let line = self.start..self.start + i + 1;
+                self.start = line.end;
+                return Ok(Some(&mut self.buf[line]));
+            }
+
+            if self.start == 0 && self.end == self.buf.len() {
+                self.start = self.end;
+                return Ok(Some(&mut self.buf[..]));
+            }
+
+            // Move the incomplete line to the front to make room for more input.
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+
+            if self.fill().await? == 0 {
+                if self.end == 0 {
+                    return Ok(None);
+                }
+                self.start = self.end;
+                return Ok(Some(&mut self.buf[..self.end]));
+            }
+        }
+    }
+
+    /// Appends more input to the buffer, returning the number of bytes read.
+    async fn fill(&mut self) -> Result<usize> {
+        let n = self
+            .conn
+            .input(self.stream.read(&mut self.buf[self.end..]))
+            .await?;
+        self.conn.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+        self.end += n;
+        Ok(n)
+    }
+
+    /// Fills `buf` with raw input, without splitting it into lines.
+    ///
+    /// Fails with `EPIPE` if the input ends first.
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result {
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.start == self.end {
+                self.start = 0;
+                self.end = 0;
+                if self.fill().await? == 0 {
+                    return Err(EPIPE);
+                }
+            }
+
+            let n = (buf.len() - filled).min(self.end - self.start);
+            buf[filled..][..n].copy_from_slice(&self.buf[self.start..][..n]);
+            self.start += n;
+            filled += n;
+        }
+        Ok(())
+    }
+
+    /// Discards `len` bytes of raw input.
+    async fn skip(&mut self, mut len: usize) -> Result {
+        let mut scratch = [0u8; 256];
+        while len > 0 {
+            let n = len.min(scratch.len());
+            self.read_exact(&mut scratch[..n]).await?;
+            len -= n;
+        }
+        Ok(())
+    }
+}
+
+/// Sends output on a connection.
+struct Writer<'a> {
+    conn: &'a Connection,
+    stream: &'a TcpStream,
+}
+
+impl Writer<'_> {
+    async fn write_all(&self, buf: &[u8]) -> Result {
+        self.conn.output(self.stream.write_all(buf)).await?;
+        self.conn
+            .bytes_out
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+async fn echo(mut input: LineReader<'_>, output: Writer<'_>) -> Result {
+    while let Some(line) = input.next().await? {
+        output.write_all(line).await?;
+    }
+    Ok(())
+}
+
+async fn discard(mut input: LineReader<'_>, _output: Writer<'_>) -> Result {
+    while input.next().await?.is_some() {}
+    Ok(())
+}
+
+async fn chargen(_input: LineReader<'_>, output: Writer<'_>) -> Result {
+    const WIDTH: usize = 72;
+    const PRINTABLE: usize = 95;
+
+    let mut line = [0u8; WIDTH + 2];
+    line[WIDTH..].copy_from_slice(b"\r\n");
+    let mut first = 0;
+
+    // This never reads, so it has to check for shutdown on its own.
+    while !output.conn.is_closing() {
+        for (i, c) in line[..WIDTH].iter_mut().enumerate() {
+            *c = b' ' + ((first + i) % PRINTABLE) as u8;
+        }
+        output.write_all(&line).await?;
+        first = (first + 1) % PRINTABLE;
+    }
+    Ok(())
+}
+
+async fn daytime(_input: LineReader<'_>, output: Writer<'_>) -> Result {
+    let mut tm = bindings::tm::default();
+    // SAFETY: `tm` is valid for writes, and `ktime_get_real_seconds` has no requirements.
+    unsafe { bindings::time64_to_tm(bindings::ktime_get_real_seconds(), 0, &mut tm) };
+
+    let line = CString::try_from_fmt(fmt!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC\r\n",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    ))?;
+    output.write_all(line.as_bytes()).await
+}
+
+async fn upper(mut input: LineReader<'_>, output: Writer<'_>) -> Result {
+    while let Some(line) = input.next().await? {
+        line.make_ascii_uppercase();
+        output.write_all(line).await?;
+    }
+    Ok(())
+}
+
+/// Parses a decimal number from a command token.
+fn parse_num<T: core::str::FromStr>(token: Option<&[u8]>) -> Option<T> {
+    core::str::from_utf8(token?).ok()?.parse().ok()
+}
+
+async fn kv(mut input: LineReader<'_>, output: Writer<'_>, server: &Server) -> Result {
+    let max_bytes = *kv_max_bytes.read();
+    let mut cmd = alloc_buffer(server.buffer_size)?;
+
+    while let Some(line) = input.next().await? {
+        if !line.ends_with(b"\n") {
+            // Skip the rest of a line that does not fit in the buffer.
+            while let Some(rest) = input.next().await? {
+                if rest.ends_with(b"\n") {
+                    break;
+                }
+            }
+            output.write_all(b"CLIENT_ERROR line too long\r\n").await?;
+            continue;
+        }
+
+        // Copy the command out of the reader so that data blocks can be read after it.
+        let len = line.len();
+        cmd[..len].copy_from_slice(line);
+        let line = &cmd[..len - 1];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut tokens = line.split(|&b| b == b' ').filter(|t| !t.is_empty());
+
+        // SAFETY: FFI call with no additional requirements.
+        let now = unsafe { bindings::ktime_get_real_seconds() };
+
+        match tokens.next() {
+            Some(b"get") => {
+                for key in tokens {
+                    let found = server.kv.lock().get(key, now)?;
+                    if let Some((flags, value)) = found {
+                        let header = CString::try_from_fmt(fmt!(" {} {}\r\n", flags, value.len()))?;
+                        output.write_all(b"VALUE ").await?;
+                        output.write_all(key).await?;
+                        output.write_all(header.as_bytes()).await?;
+                        output.write_all(&value).await?;
+                        output.write_all(b"\r\n").await?;
+                    }
+                }
+                output.write_all(b"END\r\n").await?;
+            }
+            Some(b"set") => {
+                let key = tokens.next().unwrap_or_default();
+                let flags = parse_num::<u32>(tokens.next());
+                let expiry = parse_num::<i64>(tokens.next());
+                let bytes = parse_num::<usize>(tokens.next());
+                let noreply = tokens.next() == Some(b"noreply");
+                let valid_key = !key.is_empty() && key.len() <= KV_MAX_KEY_LEN;
+                let (flags, expiry, bytes) = match (flags, expiry, bytes) {
+                    (Some(f), Some(e), Some(b)) if valid_key => (f, e, b),
+                    _ => {
+                        output
+                            .write_all(b"CLIENT_ERROR bad command line format\r\n")
+                            .await?;
+                        continue;
+                    }
+                };
+
+                if bytes > KV_MAX_VALUE_LEN {
+                    output
+                        .write_all(b"SERVER_ERROR object too large for cache\r\n")
+                        .await?;
+                    match bytes.checked_add(2) {
+                        Some(len) if len <= KV_MAX_SKIPPED_LEN => input.skip(len).await?,
+                        _ => return Ok(()),
+                    }
+                    continue;
+                }
+
+                let mut entry_key = Vec::new();
+                entry_key.try_extend_from_slice(key)?;
+                let mut value = Vec::try_with_capacity(bytes)?;
+                value.try_resize(bytes, 0)?;
+                input.read_exact(&mut value).await?;
+
+                let mut end = [0u8; 2];
+                input.read_exact(&mut end).await?;
+                if &end != b"\r\n" {
+                    output.write_all(b"CLIENT_ERROR bad data chunk\r\n").await?;
+                    continue;
+                }
+
+                let expires = match expiry {
+                    0 => 0,
+                    e if e <= KV_MAX_RELATIVE_EXPIRY => now + e,
+                    e => e,
+                };
+                let entry = KvEntry {
+                    key: entry_key,
+                    flags,
+                    expires,
+                    value,
+                };
+                let reply: &[u8] = match server.kv.lock().set(entry, max_bytes, now) {
+                    Ok(()) => b"STORED\r\n",
+                    Err(_) => b"SERVER_ERROR out of memory storing object\r\n",
+                };
+                if !noreply {
+                    output.write_all(reply).await?;
+                }
+            }
+            Some(b"delete") => {
+                let key = tokens.next().unwrap_or_default();
+                let noreply = tokens.next() == Some(b"noreply");
+                let found = server.kv.lock().delete(key, now);
+                if !noreply {
+                    let reply: &[u8] = if found {
+                        b"DELETED\r\n"
+                    } else {
+                        b"NOT_FOUND\r\n"
+                    };
+                    output.write_all(reply).await?;
+                }
+            }
+            Some(b"stats") => {
+                // SAFETY: FFI call with no additional requirements.
+                let uptime = unsafe { bindings::ktime_get_seconds() } - server.started;
+                let connections = server.active();
+                let stats = {
+                    let kv = server.kv.lock();
+                    CString::try_from_fmt(fmt!(
+                        "STAT uptime {}\r\n\
+                         STAT time {}\r\n\
+                         STAT curr_connections {}\r\n\
+                         STAT curr_items {}\r\n\
+                         STAT bytes {}\r\n\
+                         STAT limit_maxbytes {}\r\n\
+                         STAT cmd_get {}\r\n\
+                         STAT cmd_set {}\r\n\
+                         STAT get_hits {}\r\n\
+                         STAT get_misses {}\r\n\
+                         STAT delete_hits {}\r\n\
+                         STAT delete_misses {}\r\n\
+                         END\r\n",
+                        uptime,
+                        now,
+                        connections,
+                        kv.items,
+                        kv.bytes,
+                        max_bytes,
+                        kv.cmd_get,
+                        kv.cmd_set,
+                        kv.get_hits,
+                        kv.get_misses,
+                        kv.delete_hits,
+                        kv.delete_misses
+                    ))?
+                };
+                output.write_all(stats.as_bytes()).await?;
+            }
+            Some(b"quit") => return Ok(()),
+            _ => output.write_all(b"ERROR\r\n").await?,
+        }
+    }
+    Ok(())
+}
+
+/// Copies data from `from` to `to` until `from` reaches the end of its input.
+///
+/// Each chunk is written out in full before the next one is read, so that a slow `to` slows down
+/// reading from `from`. The number of bytes copied is added to `counter`.
+async fn forward(
+    conn: &Connection,
+    from: &TcpStream,
+    to: &TcpStream,
+    buf: &mut [u8],
+    counter: &AtomicU64,
+) -> Result {
     loop {
-        let n = stream.read(&mut buf).await?;
+        let n = conn.input(from.read(buf)).await?;
         if n == 0 {
             return Ok(());
         }
-        stream.write_all(&buf[..n]).await?;
+        conn.output(to.write_all(&buf[..n])).await?;
+        counter.fetch_add(n as u64, Ordering::Relaxed);
     }
 }
 
-async fn accept_loop(listener: TcpListener, executor: Arc<impl Executor>) {
+/// Polls both futures until either of them completes, returning its output.
+async fn race<T>(a: impl Future<Output = T>, b: impl Future<Output = T>) -> T {
+    let (mut a, mut b) = (a, b);
+    // SAFETY: `a` and `b` are shadowed, so they are never moved again.
+    let (mut a, mut b) = unsafe { (Pin::new_unchecked(&mut a), Pin::new_unchecked(&mut b)) };
+    future::poll_fn(|cx| match a.as_mut().poll(cx) {
+        Poll::Ready(r) => Poll::Ready(r),
+        Poll::Pending => b.as_mut().poll(cx),
+    })
+    .await
+}
+
+async fn proxy(conn: &ConnectionHandle, client: &TcpStream) -> Result {
+    let server = &conn.server;
+    let addr = server.upstream.as_ref().ok_or(EINVAL)?;
+    let remote = conn
+        .output(TcpStream::connect(net::init_ns(), addr))
+        .await?;
+
+    let mut up_buf = alloc_buffer(server.buffer_size)?;
+    let mut down_buf = alloc_buffer(server.buffer_size)?;
+
+    // There is no half-close, so the proxied connection ends as soon as either side closes.
+    race(
+        forward(conn, client, &remote, &mut up_buf, &conn.bytes_in),
+        forward(conn, &remote, client, &mut down_buf, &conn.bytes_out),
+    )
+    .await
+}
+
+async fn serve_lines(proto: Protocol, conn: &ConnectionHandle, stream: &TcpStream) -> Result {
+    let input = LineReader::try_new(conn, stream, conn.server.buffer_size)?;
+    let output = Writer { conn, stream };
+    match proto {
+        Protocol::Echo => echo(input, output).await,
+        Protocol::Discard => discard(input, output).await,
+        Protocol::Chargen => chargen(input, output).await,
+        Protocol::Daytime => daytime(input, output).await,
+        Protocol::Upper => upper(input, output).await,
+        Protocol::Kv => kv(input, output, &conn.server).await,
+        Protocol::Proxy => Err(EINVAL),
+    }
+}
+
+async fn serve(proto: Protocol, conn: ConnectionHandle, stream: TcpStream) -> Result {
+    let ret = match proto {
+        Protocol::Proxy => proxy(&conn, &stream).await,
+        _ => serve_lines(proto, &conn, &stream).await,
+    };
+
+    // Being asked to close on shutdown is not an error.
+    let counter = match ret {
+        Ok(()) | Err(ESHUTDOWN) => return Ok(()),
+        Err(ETIMEDOUT) => &conn.server.timed_out,
+        Err(_) => &conn.server.errored,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    ret
+}
+
+/// Socket options applied to accepted connections.
+struct SocketOptions {
+    nodelay: bool,
+    keepalive: bool,
+    keepalive_idle: u32,
+    keepalive_interval: u32,
+    keepalive_count: u32,
+    sndbuf: u32,
+    rcvbuf: u32,
+}
+
+impl SocketOptions {
+    fn from_params() -> Self {
+        Self {
+            nodelay: *tcp_nodelay.read(),
+            keepalive: *keepalive.read(),
+            keepalive_idle: *keepalive_idle_s.read(),
+            keepalive_interval: *keepalive_interval_s.read(),
+            keepalive_count: *keepalive_count.read(),
+            sndbuf: *sndbuf.read(),
+            rcvbuf: *rcvbuf.read(),
+        }
+    }
+
+    fn apply(&self, stream: &TcpStream) -> Result {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if self.keepalive {
+            stream.set_keepalive(true)?;
+            if self.keepalive_idle != 0 {
+                stream.set_keepalive_idle(self.keepalive_idle)?;
+            }
+            if self.keepalive_interval != 0 {
+                stream.set_keepalive_interval(self.keepalive_interval)?;
+            }
+            if self.keepalive_count != 0 {
+                stream.set_keepalive_count(self.keepalive_count)?;
+            }
+        }
+        if self.sndbuf != 0 {
+            stream.set_send_buffer_size(self.sndbuf)?;
+        }
+        if self.rcvbuf != 0 {
+            stream.set_recv_buffer_size(self.rcvbuf)?;
+        }
+        Ok(())
+    }
+}
+
+async fn accept_loop(listener: TcpListener, executor: Arc<impl Executor>, server: Arc<Server>) {
+    let max = match *max_connections.read() {
+        0 => usize::MAX,
+        n => n as usize,
+    };
+    let reject = *reject_excess.read();
+    let options = SocketOptions::from_params();
+    let mut backoff = Backoff::new();
+
     loop {
-        if let Ok(stream) = listener.accept().await {
-            let _ = spawn_task!(executor.as_arc_borrow(), echo_server(stream));
+        // Leave extra clients in the listen backlog until a connection closes.
+        if !reject {
+            server.wait_for_slot(max).await;
+        }
+
+        let stream = match listener.accept().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                server.errored.fetch_add(1, Ordering::Relaxed);
+                if server.log_allowed() {
+                    pr_warn!("Failed to accept connection: {:?}\n", e);
+                }
+                backoff.wait().await;
+                continue;
+            }
+        };
+
+        if server.active() >= max {
+            server.refused.fetch_add(1, Ordering::Relaxed);
+            if server.log_allowed() {
+                pr_info!("Rejecting connection: {max} connections already open\n");
+            }
+            continue;
+        }
+
+        if let Err(e) = options.apply(&stream) {
+            if server.log_allowed() {
+                pr_warn!("Failed to set socket options: {:?}\n", e);
+            }
+        }
+
+        let peer = stream.peer_addr().ok();
+        // Connection tasks are not tracked: the executor stops the ones left when it is dropped.
+        let spawned = Server::register(&server, peer).and_then(|conn| {
+            spawn_task!(
+                executor.as_arc_borrow(),
+                serve(server.protocol, conn, stream)
+            )
+            .map(drop)
+        });
+        match spawned {
+            Ok(()) => {
+                server.accepted.fetch_add(1, Ordering::Relaxed);
+                backoff.reset();
+            }
+            Err(e) => {
+                server.errored.fetch_add(1, Ordering::Relaxed);
+                if server.log_allowed() {
+                    pr_warn!("Failed to start connection task: {:?}\n", e);
+                }
+                backoff.wait().await;
+            }
         }
     }
 }
 
-fn start_listener(ex: ArcBorrow<'_, impl Executor + Send + Sync + 'static>) -> Result {
+fn start_listener(
+    ex: ArcBorrow<'_, impl Executor + Send + Sync + 'static>,
+    server: &Arc<Server>,
+) -> Result<Arc<dyn Task>> {
     let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::ANY, 8080));
     let listener = TcpListener::try_new(net::init_ns(), &addr)?;
-    spawn_task!(ex, accept_loop(listener, ex.into()))?;
-    Ok(())
+    spawn_task!(ex, accept_loop(listener, ex.into(), server.clone()))
+}
+
+/// Formats output into the `seq_file` being shown.
+///
+/// Writing never fails: output that does not fit is dropped, and `seq_file` then shows the file
+/// again with a larger buffer.
+struct SeqWriter(*mut bindings::seq_file);
+
+impl Write for SeqWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // SAFETY: The `seq_file` is valid while it is being shown, and `s` is valid for reads of
+        // its length.
+        unsafe { bindings::seq_write(self.0, s.as_ptr().cast(), s.len()) };
+        Ok(())
+    }
+}
+
+/// The `/proc/net/rust_echo_server` file, which is removed when this is dropped.
+struct ProcEntry {
+    entry: *mut bindings::proc_dir_entry,
+    _server: Arc<Server>,
+}
+
+// SAFETY: `entry` is only used to remove the file, which can be done from any thread.
+unsafe impl Send for ProcEntry {}
+
+// SAFETY: `ProcEntry` has no methods taking `&self`.
+unsafe impl Sync for ProcEntry {}
+
+impl ProcEntry {
+    fn try_new(server: &Arc<Server>) -> Result<Self> {
+        let server = server.clone();
+        // SAFETY: `init_net` is always valid. The file is removed in `drop`, which waits for
+        // in-progress reads, so `server` outlives every call to `show`.
+        let entry = unsafe {
+            bindings::proc_create_single_data(
+                c_str!("rust_echo_server").as_char_ptr(),
+                0o444,
+                bindings::init_net.proc_net,
+                Some(Self::show),
+                &*server as *const Server as *mut c_void,
+            )
+        };
+        if entry.is_null() {
+            return Err(ENOMEM);
+        }
+        Ok(Self {
+            entry,
+            _server: server,
+        })
+    }
+
+    unsafe extern "C" fn show(m: *mut bindings::seq_file, _: *mut c_void) -> c_int {
+        // SAFETY: `private` is the `Server` passed to `proc_create_single_data`, which is kept
+        // alive by the `ProcEntry`.
+        let server = unsafe { &*((*m).private as *const Server) };
+
+        let _ = Self::write_stats(&mut SeqWriter(m), server);
+        0
+    }
+
+    fn write_stats(out: &mut SeqWriter, server: &Server) -> fmt::Result {
+        writeln!(out, "accepted: {}", server.accepted.load(Ordering::Relaxed))?;
+        writeln!(out, "refused: {}", server.refused.load(Ordering::Relaxed))?;
+        writeln!(out, "errored: {}", server.errored.load(Ordering::Relaxed))?;
+        writeln!(
+            out,
+            "timed_out: {}",
+            server.timed_out.load(Ordering::Relaxed)
+        )?;
+        writeln!(out, "active: {}", server.active())?;
+        writeln!(
+            out,
+            "\n{:>12} {:>12} {:>10} peer",
+            "bytes_in", "bytes_out", "age_ms"
+        )?;
+
+        let now = now();
+        for conn in server.connections.lock().iter() {
+            let bytes_in = conn.bytes_in.load(Ordering::Relaxed);
+            let bytes_out = conn.bytes_out.load(Ordering::Relaxed);
+            let age = (now - conn.started) / 1_000_000;
+            write!(out, "{bytes_in:>12} {bytes_out:>12} {age:>10} ")?;
+            match &conn.peer {
+                Some(peer) => writeln!(out, "{peer}")?,
+                None => writeln!(out, "?")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ProcEntry {
+    fn drop(&mut self) {
+        // SAFETY: `entry` was returned by `proc_create_single_data` and has not been removed yet.
+        unsafe { bindings::proc_remove(self.entry) };
+    }
+}
+
+/// A workqueue created for the executor, destroyed when this is dropped.
+struct DedicatedQueue(NonNull<bindings::workqueue_struct>);
+
+// SAFETY: Workqueues can be used and destroyed from any thread.
+unsafe impl Send for DedicatedQueue {}
+
+// SAFETY: Workqueues can be used concurrently from any thread.
+unsafe impl Sync for DedicatedQueue {}
+
+impl DedicatedQueue {
+    fn try_new(flags: u32, max_active: i32) -> Result<Self> {
+        // SAFETY: The name contains no conversion specifications, so no further arguments are
+        // needed.
+        let ptr = unsafe {
+            bindings::alloc_workqueue(
+                c_str!("rust_echo_server").as_char_ptr(),
+                flags | bindings::WQ_SYSFS,
+                max_active,
+            )
+        };
+        Ok(Self(NonNull::new(ptr).ok_or(ENOMEM)?))
+    }
+
+    /// Returns the queue with a `'static` lifetime, as required by the executor.
+    ///
+    /// # Safety
+    ///
+    /// Callers must ensure that the queue is no longer used when `self` is dropped.
+    unsafe fn queue(&self) -> &'static Queue {
+        // SAFETY: `Queue` is a transparent wrapper of `workqueue_struct`, and the caller
+        // guarantees that the queue outlives its uses.
+        unsafe { &*(self.0.as_ptr() as *const Queue) }
+    }
+}
+
+impl Drop for DedicatedQueue {
+    fn drop(&mut self) {
+        // SAFETY: The queue was allocated in `try_new` and, by the safety requirements of
+        // `queue`, is no longer used.
+        unsafe { bindings::destroy_workqueue(self.0.as_ptr()) };
+    }
+}
+
+/// Returns the workqueue selected by the module parameters, creating it if it is a dedicated one.
+///
+/// The dedicated queue must outlive the executor using the returned queue.
+fn select_queue() -> Result<(&'static Queue, Option<DedicatedQueue>)> {
+    Ok(match workqueue.read() {
+        b"system" => (kernel::workqueue::system(), None),
+        b"system_highpri" => (kernel::workqueue::system_highpri(), None),
+        b"system_unbound" => (kernel::workqueue::system_unbound(), None),
+        b"system_long" => (kernel::workqueue::system_long(), None),
+        b"dedicated" => {
+            let mut flags = 0;
+            if *wq_unbound.read() {
+                flags |= bindings::WQ_UNBOUND;
+            }
+            if *wq_highpri.read() {
+                flags |= bindings::WQ_HIGHPRI;
+            }
+            let max_active = (*wq_max_active.read()).try_into()?;
+            let dedicated = DedicatedQueue::try_new(flags, max_active)?;
+            // SAFETY: The caller keeps `dedicated` alive for as long as the executor.
+            (unsafe { dedicated.queue() }, Some(dedicated))
+        }
+        name => {
+            pr_err!("Unknown workqueue: {}\n", core::str::from_utf8(name)?);
+            return Err(EINVAL);
+        }
+    })
 }
 
 struct RustEchoServer {
+    _proc: ProcEntry,
+    server: Arc<Server>,
+    /// The task running the accept loop, stopped first when the module is unloaded.
+    accept_task: Option<Arc<dyn Task>>,
     _handle: AutoStopHandle<dyn Executor>,
+    /// Declared after `_handle` so that it is destroyed after the executor is stopped.
+    _queue: Option<DedicatedQueue>,
 }
 
 impl kernel::Module for RustEchoServer {
     fn init(_name: &'static CStr, _module: &'static ThisModule) -> Result<Self> {
-        let handle = WqExecutor::try_new(kernel::workqueue::system())?;
-        start_listener(handle.executor())?;
+        let proto = Protocol::from_name(protocol.read())?;
+
+        let size = *buffer_size.read() as usize;
+        if !(MIN_BUFFER_SIZE..=MAX_BUFFER_SIZE).contains(&size) {
+            pr_err!("buffer_size must be between {MIN_BUFFER_SIZE} and {MAX_BUFFER_SIZE}\n");
+            return Err(EINVAL);
+        }
+
+        let upstream_addr = match proto {
+            Protocol::Proxy => Some(parse_ipv4_addr(upstream.read()).map_err(|e| {
+                pr_err!("upstream must be of the form a.b.c.d:port\n");
+                e
+            })?),
+            _ => None,
+        };
+
+        let server = Server::try_new(proto, size, upstream_addr)?;
+        let proc = ProcEntry::try_new(&server)?;
+        let (queue, dedicated) = select_queue()?;
+        let handle = WqExecutor::try_new(queue)?;
+        let accept_task = start_listener(handle.executor(), &server)?;
         Ok(Self {
+            _proc: proc,
+            server,
+            accept_task: Some(accept_task),
             _handle: handle.into(),
+            _queue: dedicated,
         })
     }
 }
 
+impl Drop for RustEchoServer {
+    fn drop(&mut self) {
+        // Stop accepting new connections; dropping the accept loop closes the listener.
+        if let Some(task) = self.accept_task.take() {
+            task.sync_stop();
+        }
+
+        let grace = Duration::from_millis((*shutdown_grace_ms.read()).into());
+        let aborted = self.server.drain(grace);
+        if aborted != 0 {
+            pr_info!("Aborting {aborted} connection(s) still open after the grace period\n");
+        }
+
+        // Dropping `_handle` stops the executor, which drops the remaining tasks along with
+        // their sockets.
+    }
+}
+
 module! {
     type: RustEchoServer,
     name: "rust_echo_server",
     author: "Rust for Linux Contributors",
     description: "Rust tcp echo sample",
     license: "GPL v2",
+    params: {
+        workqueue: str {
+            default: b"system",
+            permissions: 0o444,
+            description: "Executor workqueue: system[_highpri|_unbound|_long] or dedicated",
+        },
+        wq_unbound: bool {
+            default: false,
+            permissions: 0o444,
+            description: "Make the dedicated workqueue unbound",
+        },
+        wq_highpri: bool {
+            default: false,
+            permissions: 0o444,
+            description: "Make the dedicated workqueue high priority",
+        },
+        wq_max_active: u32 {
+            default: 0,
+            permissions: 0o444,
+            description: "Max in-flight work items on the dedicated workqueue (0 for default)",
+        },
+        protocol: str {
+            default: b"echo",
+            permissions: 0o444,
+            description: "Protocol: echo, discard, chargen, daytime, upper, kv or proxy",
+        },
+        upstream: str {
+            default: b"127.0.0.1:8081",
+            permissions: 0o444,
+            description: "Address connections are forwarded to by the proxy protocol",
+        },
+        buffer_size: u32 {
+            default: 16384,
+            permissions: 0o444,
+            description: "Size of the I/O buffers of each connection (bytes)",
+        },
+        kv_max_bytes: usize {
+            default: 16777216,
+            permissions: 0o444,
+            description: "Maximum size of the items held by the kv protocol, overhead included",
+        },
+        tcp_nodelay: bool {
+            default: false,
+            permissions: 0o444,
+            description: "Set TCP_NODELAY on accepted connections",
+        },
+        keepalive: bool {
+            default: false,
+            permissions: 0o444,
+            description: "Set SO_KEEPALIVE on accepted connections",
+        },
+        keepalive_idle_s: u32 {
+            default: 0,
+            permissions: 0o444,
+            description: "Idle time before keepalive probes are sent (s, 0 for default)",
+        },
+        keepalive_interval_s: u32 {
+            default: 0,
+            permissions: 0o444,
+            description: "Time between keepalive probes (s, 0 for default)",
+        },
+        keepalive_count: u32 {
+            default: 0,
+            permissions: 0o444,
+            description: "Unanswered keepalive probes before closing (0 for default)",
+        },
+        sndbuf: u32 {
+            default: 0,
+            permissions: 0o444,
+            description: "Send buffer size of accepted connections (bytes, 0 for default)",
+        },
+        rcvbuf: u32 {
+            default: 0,
+            permissions: 0o444,
+            description: "Receive buffer size of accepted connections (bytes, 0 for default)",
+        },
+        max_connections: u32 {
+            default: 256,
+            permissions: 0o444,
+            description: "Maximum number of concurrent connections (0 for no limit)",
+        },
+        reject_excess: bool {
+            default: false,
+            permissions: 0o444,
+            description: "Close clients over the limit instead of leaving them in the backlog",
+        },
+        idle_timeout_ms: u32 {
+            default: 60000,
+            permissions: 0o444,
+            description: "Close connections idle for longer than this (ms, 0 for no limit)",
+        },
+        max_lifetime_ms: u32 {
+            default: 0,
+            permissions: 0o444,
+            description: "Close connections open for longer than this (ms, 0 for no limit)",
+        },
+        shutdown_grace_ms: u32 {
+            default: 5000,
+            permissions: 0o444,
+            description: "Time given to in-flight connections to complete on unload (ms)",
+        },
+    },
 }
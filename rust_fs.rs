@@ -1,59 +1,1709 @@
 // SPDX-License-Identifier: GPL-2.0
 
 //! Rust file system sample.
+//!
+//! `rustfs` is an in-memory file system similar to `ramfs`: files and directories only live in the
+//! dentry and page caches, so they are kept until they are removed or the file system is
+//! unmounted, e.g., after `mount -t rustfs none /mnt`.
+//!
+//! The `mode`, `uid` and `gid` mount options set the permissions and owner of the root directory,
+//! and `size` and `nr_inodes` limit the size of the contents and the number of inodes, with an
+//! optional `k`, `m` or `g` suffix. Writes beyond the limits fail with `ENOSPC`, and `statfs`
+//! reports the usage against them. Files are charged for their whole size, including holes.
+//! Options other than the defaults are listed in `/proc/mounts`.
+//!
+//! Symbolic links, hard links and renames are supported, including renames with
+//! `RENAME_NOREPLACE` and `RENAME_EXCHANGE`.
+//!
+//! Extended attributes in the `user.`, `trusted.` and `security.` namespaces are kept in memory, up
+//! to 64 KiB of names and values per inode, so that file capabilities and security labels can be
+//! set. With the `acl` mount option, POSIX ACLs are supported too.
+//!
+//! `tarfs` is a read-only file system showing the contents of a tar archive, given as the source of
+//! the mount, e.g., `mount -t tarfs image.tar /mnt`. The image can be a regular file or a block
+//! device, such as a loop device. Directories, regular files, symbolic and hard links, device
+//! nodes and FIFOs are shown with the permissions, owners and modification times stored in the
+//! archive. Long names in GNU and pax extension headers are supported. As when extracting the
+//! archive, an entry replaces the earlier one with the same name, except that a directory is only
+//! updated by a later directory entry and kept otherwise. Entries of unsupported types and hard
+//! links to missing entries or to directories are skipped with a warning. The contents of files
+//! are read from the image into the page cache as they are accessed, so they can also be mapped.
 
+use core::cmp::min;
+use core::ffi::{c_char, c_int, c_ulong, c_void};
+use core::sync::atomic::{AtomicU64, Ordering};
 use kernel::prelude::*;
-use kernel::{c_str, fs};
+use kernel::{bindings, c_str, error::to_result, file, fs, mutex_init, str::CString, sync::Mutex};
 
-module_fs! {
-    type: RustFs,
+module! {
+    type: RustFsModule,
     name: "rust_fs",
     author: "Rust for Linux Contributors",
     license: "GPL",
 }
 
+struct RustFsModule {
+    _rustfs: Pin<Box<fs::Registration>>,
+    _tarfs: Pin<Box<fs::Registration>>,
+}
+
+impl kernel::Module for RustFsModule {
+    fn init(_name: &'static CStr, module: &'static ThisModule) -> Result<Self> {
+        Ok(Self {
+            _rustfs: fs::Registration::new_pinned::<RustFs>(module)?,
+            _tarfs: fs::Registration::new_pinned::<TarFs>(module)?,
+        })
+    }
+}
+
+/// The permissions of the root directory unless the `mode` option is given.
+const DEFAULT_MODE: u32 = 0o755;
+
+/// The maximum size of the names and values of the extended attributes of an inode.
+const XATTRS_MAX: usize = 64 * 1024;
+
+/// The value of invalid user and group ids (`INVALID_UID` and `INVALID_GID`).
+const INVALID_ID: u32 = u32::MAX;
+
+/// The options of a mount, e.g., `mount -t rustfs -o mode=700,uid=1000,size=64m none /mnt`.
+struct MountOptions {
+    /// The permissions of the root directory.
+    mode: u32,
+
+    /// The owner of the root directory, in the user namespace of the mount.
+    uid: u32,
+
+    /// The group of the root directory, in the user namespace of the mount.
+    gid: u32,
+
+    /// The maximum size of the contents in bytes, or zero for no limit.
+    size: u64,
+
+    /// The maximum number of inodes, or zero for no limit.
+    nr_inodes: u64,
+
+    /// Whether POSIX ACLs are supported.
+    acl: bool,
+}
+
+/// Checks that `id` is a valid user or group id.
+fn parse_id(id: u32) -> Result<u32> {
+    if id == INVALID_ID {
+        return Err(EINVAL);
+    }
+    Ok(id)
+}
+
+/// Parses a number of bytes with an optional `k`, `m` or `g` suffix, as `memparse` does.
+fn parse_size(value: &CStr) -> Result<u64> {
+    let bytes = value.as_bytes();
+    let (digits, shift) = match bytes.last() {
+        Some(b'k' | b'K') => (&bytes[..bytes.len() - 1], 10),
+        Some(b'm' | b'M') => (&bytes[..bytes.len() - 1], 20),
+        Some(b'g' | b'G') => (&bytes[..bytes.len() - 1], 30),
+        _ => (bytes, 0),
+    };
+    let size = core::str::from_utf8(digits)
+        .map_err(|_| EINVAL)?
+        .parse::<u64>()
+        .map_err(|_| EINVAL)?;
+    size.checked_mul(1 << shift).ok_or(EINVAL)
+}
+
+/// Returns the number of pages holding `size` bytes.
+fn pages(size: u64) -> u64 {
+    (size >> bindings::PAGE_SHIFT) + u64::from(size % kernel::PAGE_SIZE as u64 != 0)
+}
+
+/// Returns the `super_block` wrapped by `sb`.
+fn raw_sb<T: fs::Type + ?Sized>(sb: &fs::SuperBlock<T>) -> *mut bindings::super_block {
+    // `SuperBlock` is a transparent wrapper around `bindings::super_block`.
+    sb as *const fs::SuperBlock<T> as *mut bindings::super_block
+}
+
+/// Returns `ptr`, or the error it encodes, as `IS_ERR` and `PTR_ERR` do.
+fn from_err_ptr<T>(ptr: *mut T) -> Result<*mut T> {
+    let value = ptr as isize;
+    if value < 0 && value >= -(bindings::MAX_ERRNO as isize) {
+        to_result(value as c_int)?;
+    }
+    Ok(ptr)
+}
+
+/// Converts the result of an inode operation to the value returned to the VFS.
+fn to_errno(result: Result) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e.to_errno(),
+    }
+}
+
+/// An extended attribute.
+struct Xattr {
+    /// The full name, including the prefix of its namespace.
+    name: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Xattr {
+    fn is(&self, prefix: &[u8], name: &[u8]) -> bool {
+        self.name.len() == prefix.len() + name.len()
+            && self.name.starts_with(prefix)
+            && self.name.ends_with(name)
+    }
+}
+
+/// The extended attributes of an inode.
+struct Xattrs {
+    list: Vec<Xattr>,
+
+    /// The size of the names and values in `list`.
+    size: usize,
+}
+
+impl Xattrs {
+    fn get(&self, prefix: &[u8], name: &[u8]) -> Option<&Xattr> {
+        self.list.iter().find(|xattr| xattr.is(prefix, name))
+    }
+
+    /// Sets or, if `value` is `None`, removes an attribute, with the `XATTR_*` `flags`.
+    fn set(&mut self, prefix: &[u8], name: &[u8], value: Option<&[u8]>, flags: u32) -> Result {
+        let index = self.list.iter().position(|xattr| xattr.is(prefix, name));
+        if index.is_some() && flags & bindings::XATTR_CREATE != 0 {
+            return Err(EEXIST);
+        }
+        let (index, value) = match (index, value) {
+            (Some(index), None) => {
+                let xattr = self.list.swap_remove(index);
+                self.size -= xattr.name.len() + xattr.value.len();
+                return Ok(());
+            }
+            (None, None) => return Err(ENODATA),
+            (None, Some(_)) if flags & bindings::XATTR_REPLACE != 0 => return Err(ENODATA),
+            (index, Some(value)) => (index, value),
+        };
+
+        let old = index.map_or(0, |index| self.list[index].value.len());
+        let added = if index.is_some() {
+            0
+        } else {
+            prefix.len() + name.len()
+        };
+        let size = self.size - old + added + value.len();
+        if size > XATTRS_MAX {
+            return Err(ENOSPC);
+        }
+        let value = try_to_vec(value)?;
+        match index {
+            Some(index) => self.list[index].value = value,
+            None => {
+                let mut full_name = Vec::new();
+                full_name.try_extend_from_slice(prefix)?;
+                full_name.try_extend_from_slice(name)?;
+                self.list.try_push(Xattr {
+                    name: full_name,
+                    value,
+                })?;
+            }
+        }
+        self.size = size;
+        Ok(())
+    }
+}
+
+/// The output buffer of `listxattr`, or only the length of the list if it is empty.
+struct XattrList {
+    buf: *mut c_char,
+    size: usize,
+    len: usize,
+}
+
+impl XattrList {
+    /// Appends `name` and its NUL terminator, failing with `ERANGE` if the buffer is too small.
+    fn push(&mut self, name: &[u8]) -> Result {
+        let len = self.len + name.len() + 1;
+        if self.size != 0 {
+            if len > self.size {
+                return Err(ERANGE);
+            }
+            // SAFETY: The buffer is valid for writes of `size` bytes, which covers `len`.
+            unsafe {
+                let dst = self.buf.add(self.len) as *mut u8;
+                core::ptr::copy_nonoverlapping(name.as_ptr(), dst, name.len());
+                *dst.add(name.len()) = 0;
+            }
+        }
+        self.len = len;
+        Ok(())
+    }
+}
+
+/// The state of an inode, to which `inode::i_private` points.
+struct InodeInfo {
+    /// The number of pages charged to the inode.
+    charged: AtomicU64,
+
+    /// The extended attributes, other than the ACLs that the VFS caches in the inode.
+    xattrs: Mutex<Xattrs>,
+}
+
+impl InodeInfo {
+    /// Allocates the state of a new inode, to be freed with [`InodeInfo::free`].
+    fn try_new() -> Result<*mut Self> {
+        let mut info = Pin::from(Box::try_new(Self {
+            charged: AtomicU64::new(0),
+            // SAFETY: `mutex_init!` is called below.
+            xattrs: unsafe {
+                Mutex::new(Xattrs {
+                    list: Vec::new(),
+                    size: 0,
+                })
+            },
+        })?);
+        // SAFETY: `xattrs` is pinned when `info` is.
+        let xattrs = unsafe { info.as_mut().map_unchecked_mut(|info| &mut info.xattrs) };
+        mutex_init!(xattrs, "InodeInfo::xattrs");
+        // SAFETY: The state is only used through the returned pointer, so it is never moved.
+        Ok(Box::into_raw(unsafe { Pin::into_inner_unchecked(info) }))
+    }
+
+    /// Frees the state of `inode` and returns the number of pages that were charged to it.
+    ///
+    /// The state of the root is missing if mounting failed before it was allocated.
+    ///
+    /// # Safety
+    ///
+    /// `inode` must be an inode of this file system that is being evicted.
+    unsafe fn free(inode: *mut bindings::inode) -> u64 {
+        // SAFETY: `i_private` is null or was set from `InodeInfo::try_new`, and the inode is no
+        // longer used.
+        unsafe {
+            let info = (*inode).i_private as *mut Self;
+            if info.is_null() {
+                return 0;
+            }
+            Box::from_raw(info).charged.into_inner()
+        }
+    }
+
+    /// Returns the state of `inode`.
+    ///
+    /// # Safety
+    ///
+    /// `inode` must be a referenced inode of this file system.
+    unsafe fn of<'a>(inode: *mut bindings::inode) -> &'a Self {
+        // SAFETY: The pointer is set from `InodeInfo::try_new` before the inode is used, and is
+        // only freed once it is evicted.
+        unsafe { &*((*inode).i_private as *const Self) }
+    }
+}
+
+/// The state of a mounted `rustfs`.
+///
+/// The operation tables are filled in at runtime, as the kernel structures have too many fields
+/// to be listed in constants. They live as long as the superblock, which outlives its inodes.
+struct FsInfo {
+    options: MountOptions,
+
+    /// The limit of `used_pages`, or zero for no limit.
+    max_pages: u64,
+
+    /// The number of pages charged to files.
+    used_pages: AtomicU64,
+
+    /// The number of inodes, including the root.
+    used_inodes: AtomicU64,
+
+    super_ops: bindings::super_operations,
+    dir_inode_ops: bindings::inode_operations,
+    file_inode_ops: bindings::inode_operations,
+    symlink_inode_ops: bindings::inode_operations,
+    special_inode_ops: bindings::inode_operations,
+    file_ops: bindings::file_operations,
+    user_xattr: bindings::xattr_handler,
+    trusted_xattr: bindings::xattr_handler,
+    security_xattr: bindings::xattr_handler,
+
+    /// The NULL-terminated list of handlers pointed to by `super_block::s_xattr`.
+    xattr_handlers: [*const bindings::xattr_handler; 6],
+}
+
+// SAFETY: The operation tables are only read after the superblock is set up, from any thread.
+unsafe impl Send for FsInfo {}
+
+// SAFETY: The counters are atomic, and the rest is only modified before the superblock is set up.
+unsafe impl Sync for FsInfo {}
+
+impl FsInfo {
+    fn try_new(options: MountOptions) -> Result<Box<Self>> {
+        // SAFETY: All fields of the operation tables are pointers or optional function pointers,
+        // for which zero is a valid value. The options and counters are plain integers.
+        let mut info = Box::try_new(unsafe { core::mem::zeroed::<Self>() })?;
+        info.max_pages = pages(options.size);
+        info.options = options;
+        info.used_inodes = AtomicU64::new(1);
+
+        let dir = &mut info.dir_inode_ops;
+        dir.create = Some(RustFs::create);
+        dir.lookup = Some(bindings::simple_lookup);
+        dir.unlink = Some(bindings::simple_unlink);
+        dir.mkdir = Some(RustFs::mkdir);
+        dir.rmdir = Some(bindings::simple_rmdir);
+        dir.mknod = Some(RustFs::mknod);
+        dir.symlink = Some(RustFs::symlink);
+        dir.link = Some(bindings::simple_link);
+        dir.rename = Some(bindings::simple_rename);
+        dir.setattr = Some(RustFs::setattr);
+        dir.listxattr = Some(RustFs::listxattr);
+
+        let file = &mut info.file_inode_ops;
+        file.setattr = Some(RustFs::setattr);
+        file.getattr = Some(bindings::simple_getattr);
+        file.listxattr = Some(RustFs::listxattr);
+
+        // SAFETY: The table is a valid static.
+        info.symlink_inode_ops =
+            unsafe { core::ptr::read(&bindings::page_symlink_inode_operations) };
+        info.symlink_inode_ops.listxattr = Some(RustFs::listxattr);
+
+        let special = &mut info.special_inode_ops;
+        special.setattr = Some(RustFs::setattr);
+        special.listxattr = Some(RustFs::listxattr);
+
+        #[cfg(CONFIG_FS_POSIX_ACL)]
+        for ops in [
+            &mut info.dir_inode_ops,
+            &mut info.file_inode_ops,
+            &mut info.special_inode_ops,
+        ] {
+            ops.set_acl = Some(bindings::simple_set_acl);
+        }
+
+        info.user_xattr.prefix = c_str!("user.").as_char_ptr();
+        info.trusted_xattr.prefix = c_str!("trusted.").as_char_ptr();
+        // Security modules check the permission to set their attributes, e.g., `CAP_SETFCAP` for
+        // `security.capability`, before the handler is called.
+        info.security_xattr.prefix = c_str!("security.").as_char_ptr();
+        for handler in [
+            &mut info.user_xattr,
+            &mut info.trusted_xattr,
+            &mut info.security_xattr,
+        ] {
+            handler.get = Some(RustFs::xattr_get);
+            handler.set = Some(RustFs::xattr_set);
+        }
+        info.xattr_handlers[0] = &info.user_xattr;
+        info.xattr_handlers[1] = &info.trusted_xattr;
+        info.xattr_handlers[2] = &info.security_xattr;
+
+        let fops = &mut info.file_ops;
+        fops.read_iter = Some(bindings::generic_file_read_iter);
+        fops.write_iter = Some(RustFs::write_iter);
+        fops.mmap = Some(bindings::generic_file_mmap);
+        fops.fsync = Some(bindings::noop_fsync);
+        fops.splice_read = Some(bindings::generic_file_splice_read);
+        fops.splice_write = Some(bindings::iter_file_splice_write);
+        fops.llseek = Some(bindings::generic_file_llseek);
+
+        Ok(info)
+    }
+
+    /// Returns the [`FsInfo`] of `sb`.
+    ///
+    /// # Safety
+    ///
+    /// `sb` must be a valid `rustfs` superblock.
+    unsafe fn get<'a>(sb: *mut bindings::super_block) -> &'a Self {
+        // SAFETY: `s_fs_info` is the `FsInfo` passed to `NewSuperBlock::init`, which is freed
+        // with the superblock.
+        unsafe { &*((*sb).s_fs_info as *const Self) }
+    }
+
+    /// Adds `count` to `used`, failing with `ENOSPC` if it would exceed `max`, unless zero.
+    fn reserve(used: &AtomicU64, max: u64, count: u64) -> Result {
+        used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+            n.checked_add(count).filter(|&n| max == 0 || n <= max)
+        })
+        .map(|_| ())
+        .map_err(|_| ENOSPC)
+    }
+
+    /// Charges `inode` for `size` bytes if it is charged for less.
+    ///
+    /// # Safety
+    ///
+    /// `inode` must be a regular file or a symbolic link of this file system.
+    unsafe fn charge(&self, inode: *mut bindings::inode, size: u64) -> Result {
+        // SAFETY: `inode` is valid by the safety requirements.
+        let charged = unsafe { &InodeInfo::of(inode).charged };
+        let wanted = pages(size);
+        let mut current = charged.load(Ordering::Relaxed);
+        while current < wanted {
+            Self::reserve(&self.used_pages, self.max_pages, wanted - current)?;
+            match charged.compare_exchange(current, wanted, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(n) => {
+                    self.used_pages
+                        .fetch_sub(wanted - current, Ordering::Relaxed);
+                    current = n;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Charges `inode` for its size once it has been written or truncated.
+    ///
+    /// This releases the pages of short writes, and may exceed the limit if writes to the same
+    /// file raced with each other, as one of them may have released the pages of another.
+    ///
+    /// # Safety
+    ///
+    /// `inode` must be a regular file of this file system.
+    unsafe fn settle(&self, inode: *mut bindings::inode) {
+        // SAFETY: `inode` is valid by the safety requirements.
+        let (charged, size) = unsafe { (&InodeInfo::of(inode).charged, Self::size(inode)) };
+        let wanted = pages(size);
+        let old = charged.swap(wanted, Ordering::Relaxed);
+        if wanted > old {
+            self.used_pages.fetch_add(wanted - old, Ordering::Relaxed);
+        } else {
+            self.used_pages.fetch_sub(old - wanted, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the size of `inode`, which may be changed concurrently, as `i_size_read` does.
+    ///
+    /// # Safety
+    ///
+    /// `inode` must be valid.
+    unsafe fn size(inode: *const bindings::inode) -> u64 {
+        // SAFETY: `inode` is valid by the safety requirements.
+        unsafe { core::ptr::read_volatile(core::ptr::addr_of!((*inode).i_size)) as u64 }
+    }
+}
+
 struct RustFs;
 
 #[vtable]
 impl fs::Context<Self> for RustFs {
-    type Data = ();
+    type Data = Box<MountOptions>;
 
-    kernel::define_fs_params! {(),
-        {flag, "flag", |_, v| { pr_info!("flag passed-in: {v}\n"); Ok(()) } },
-        {flag_no, "flagno", |_, v| { pr_info!("flagno passed-in: {v}\n"); Ok(()) } },
-        {bool, "bool", |_, v| { pr_info!("bool passed-in: {v}\n"); Ok(()) } },
-        {u32, "u32", |_, v| { pr_info!("u32 passed-in: {v}\n"); Ok(()) } },
-        {u32oct, "u32oct", |_, v| { pr_info!("u32oct passed-in: {v}\n"); Ok(()) } },
-        {u32hex, "u32hex", |_, v| { pr_info!("u32hex passed-in: {v}\n"); Ok(()) } },
-        {s32, "s32", |_, v| { pr_info!("s32 passed-in: {v}\n"); Ok(()) } },
-        {u64, "u64", |_, v| { pr_info!("u64 passed-in: {v}\n"); Ok(()) } },
-        {string, "string", |_, v| { pr_info!("string passed-in: {v}\n"); Ok(()) } },
-        {enum, "enum", [("first", 10), ("second", 20)], |_, v| {
-            pr_info!("enum passed-in: {v}\n"); Ok(()) }
-        },
+    kernel::define_fs_params! {Box<MountOptions>,
+        {u32oct, "mode", |o, v| {
+            if v & !bindings::S_IALLUGO != 0 {
+                return Err(EINVAL);
+            }
+            o.mode = v;
+            Ok(())
+        } },
+        {u32, "uid", |o, v| { o.uid = parse_id(v)?; Ok(()) } },
+        {u32, "gid", |o, v| { o.gid = parse_id(v)?; Ok(()) } },
+        {string, "size", |o, v| { o.size = parse_size(v)?; Ok(()) } },
+        {string, "nr_inodes", |o, v| { o.nr_inodes = parse_size(v)?; Ok(()) } },
+        {flag, "acl", |o, v| {
+            if !cfg!(CONFIG_FS_POSIX_ACL) {
+                return Err(EINVAL);
+            }
+            o.acl = v;
+            Ok(())
+        } },
     }
 
-    fn try_new() -> Result {
-        pr_info!("context created!\n");
-        Ok(())
+    fn try_new() -> Result<Self::Data> {
+        Ok(Box::try_new(MountOptions {
+            mode: DEFAULT_MODE,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            nr_inodes: 0,
+            acl: false,
+        })?)
+    }
+}
+
+impl RustFs {
+    /// Creates an inode of `mode` in `sb`, like `ramfs_get_inode`.
+    ///
+    /// # Safety
+    ///
+    /// `sb` must be a valid `rustfs` superblock, and `dir` a directory inode of it.
+    unsafe fn new_inode(
+        mnt_userns: *mut bindings::user_namespace,
+        sb: *mut bindings::super_block,
+        dir: *const bindings::inode,
+        mode: bindings::umode_t,
+        dev: bindings::dev_t,
+    ) -> Result<*mut bindings::inode> {
+        // SAFETY: `sb` is valid by the safety requirements.
+        let info = unsafe { FsInfo::get(sb) };
+        FsInfo::reserve(&info.used_inodes, info.options.nr_inodes, 1)?;
+
+        let inode_info = match InodeInfo::try_new() {
+            Ok(inode_info) => inode_info,
+            Err(e) => {
+                info.used_inodes.fetch_sub(1, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+        // SAFETY: `sb` is valid by the safety requirements.
+        let inode = unsafe { bindings::new_inode(sb) };
+        if inode.is_null() {
+            info.used_inodes.fetch_sub(1, Ordering::Relaxed);
+            // SAFETY: The state was just allocated and is not used anywhere else.
+            drop(unsafe { Box::from_raw(inode_info) });
+            return Err(ENOMEM);
+        }
+
+        // SAFETY: `inode` was just allocated, so nothing else uses it yet. `sb` and `dir` are
+        // valid by the safety requirements.
+        unsafe {
+            (*inode).i_ino = bindings::get_next_ino().into();
+            (*inode).i_private = inode_info as *mut c_void;
+            bindings::inode_init_owner(mnt_userns, inode, dir, mode);
+
+            // The pages are the only copy of the data, so they must not be reclaimed. This is
+            // what `mapping_set_unevictable` does.
+            let mapping = (*inode).i_mapping;
+            (*mapping).a_ops = &bindings::ram_aops;
+            bindings::set_bit(
+                bindings::mapping_flags_AS_UNEVICTABLE.into(),
+                core::ptr::addr_of_mut!((*mapping).flags),
+            );
+
+            let now = bindings::current_time(inode);
+            (*inode).i_atime = now;
+            (*inode).i_mtime = now;
+            (*inode).i_ctime = now;
+
+            match u32::from(mode) & bindings::S_IFMT {
+                bindings::S_IFREG => {
+                    (*inode).i_op = &info.file_inode_ops;
+                    (*inode).i_fop = &info.file_ops;
+                }
+                bindings::S_IFLNK => {
+                    (*inode).i_op = &info.symlink_inode_ops;
+                    bindings::inode_nohighmem(inode);
+                }
+                bindings::S_IFDIR => {
+                    (*inode).i_op = &info.dir_inode_ops;
+                    (*inode).i_fop = &bindings::simple_dir_operations;
+                    // Directories start with two links, counting their `.` entry.
+                    bindings::inc_nlink(inode);
+                }
+                _ => {
+                    bindings::init_special_inode(inode, mode, dev);
+                    (*inode).i_op = &info.special_inode_ops;
+                }
+            }
+        }
+
+        // New inodes inherit the default ACL of their directory, which may change their mode.
+        #[cfg(CONFIG_FS_POSIX_ACL)]
+        // SAFETY: `dir` is valid by the safety requirements, and `inode` is not used elsewhere.
+        if let Err(e) = to_result(unsafe { bindings::simple_acl_create(dir as *mut _, inode) }) {
+            // SAFETY: Evicting the inode releases what was reserved for it.
+            unsafe { bindings::iput(inode) };
+            return Err(e);
+        }
+        Ok(inode)
+    }
+
+    unsafe extern "C" fn mknod(
+        mnt_userns: *mut bindings::user_namespace,
+        dir: *mut bindings::inode,
+        dentry: *mut bindings::dentry,
+        mode: bindings::umode_t,
+        dev: bindings::dev_t,
+    ) -> c_int {
+        // SAFETY: The VFS passes a `rustfs` directory, locked, and a negative dentry in it.
+        to_errno(unsafe {
+            Self::new_inode(mnt_userns, (*dir).i_sb, dir, mode, dev)
+                .map(|inode| Self::instantiate(dir, dentry, inode))
+        })
+    }
+
+    unsafe extern "C" fn symlink(
+        mnt_userns: *mut bindings::user_namespace,
+        dir: *mut bindings::inode,
+        dentry: *mut bindings::dentry,
+        target: *const c_char,
+    ) -> c_int {
+        let mode = (bindings::S_IFLNK | 0o777) as bindings::umode_t;
+        // SAFETY: The VFS passes a `rustfs` directory, locked, a negative dentry in it, and a
+        // NUL-terminated target.
+        to_errno(unsafe {
+            Self::new_inode(mnt_userns, (*dir).i_sb, dir, mode, 0).and_then(|inode| {
+                // The target is stored with its NUL terminator in the first page of the inode.
+                let len = CStr::from_char_ptr(target).len() + 1;
+                let ret = FsInfo::get((*dir).i_sb)
+                    .charge(inode, len as u64)
+                    .and_then(|()| {
+                        let len = c_int::try_from(len).map_err(|_| ENAMETOOLONG)?;
+                        to_result(bindings::page_symlink(inode, target, len))
+                    });
+                match ret {
+                    Ok(()) => Self::instantiate(dir, dentry, inode),
+                    // Evicting the inode releases what was charged for it.
+                    Err(_) => bindings::iput(inode),
+                }
+                ret
+            })
+        })
+    }
+
+    /// Makes the negative `dentry` in `dir` refer to the new `inode`.
+    ///
+    /// # Safety
+    ///
+    /// `dir` must be a locked `rustfs` directory, `dentry` a negative dentry in it, and the
+    /// reference to `inode` is taken over.
+    unsafe fn instantiate(
+        dir: *mut bindings::inode,
+        dentry: *mut bindings::dentry,
+        inode: *mut bindings::inode,
+    ) {
+        // SAFETY: The pointers are valid by the safety requirements.
+        unsafe {
+            bindings::d_instantiate(dentry, inode);
+            // The extra reference keeps the dentry, and therefore the inode, in the cache until
+            // it is removed, as nothing else holds on to it.
+            bindings::dget(dentry);
+            let now = bindings::current_time(dir);
+            (*dir).i_mtime = now;
+            (*dir).i_ctime = now;
+        }
+    }
+
+    unsafe extern "C" fn create(
+        mnt_userns: *mut bindings::user_namespace,
+        dir: *mut bindings::inode,
+        dentry: *mut bindings::dentry,
+        mode: bindings::umode_t,
+        _excl: bool,
+    ) -> c_int {
+        // SAFETY: The VFS passes valid arguments, as for `mknod`.
+        unsafe { Self::mknod(mnt_userns, dir, dentry, mode | bindings::S_IFREG as u16, 0) }
+    }
+
+    unsafe extern "C" fn mkdir(
+        mnt_userns: *mut bindings::user_namespace,
+        dir: *mut bindings::inode,
+        dentry: *mut bindings::dentry,
+        mode: bindings::umode_t,
+    ) -> c_int {
+        // SAFETY: The VFS passes valid arguments, as for `mknod`.
+        let ret =
+            unsafe { Self::mknod(mnt_userns, dir, dentry, mode | bindings::S_IFDIR as u16, 0) };
+        if ret == 0 {
+            // SAFETY: `dir` is valid and locked. The `..` entry of the new directory links to it.
+            unsafe { bindings::inc_nlink(dir) };
+        }
+        ret
+    }
+
+    unsafe extern "C" fn show_options(
+        seq: *mut bindings::seq_file,
+        root: *mut bindings::dentry,
+    ) -> c_int {
+        // SAFETY: The VFS passes the root of a mounted `rustfs`.
+        let options = unsafe { &FsInfo::get((*root).d_sb).options };
+
+        // SAFETY: `seq` is valid, and the formats match the arguments.
+        unsafe {
+            if options.mode != DEFAULT_MODE {
+                bindings::seq_printf(seq, c_str!(",mode=%o").as_char_ptr(), options.mode);
+            }
+            if options.uid != 0 {
+                bindings::seq_printf(seq, c_str!(",uid=%u").as_char_ptr(), options.uid);
+            }
+            if options.gid != 0 {
+                bindings::seq_printf(seq, c_str!(",gid=%u").as_char_ptr(), options.gid);
+            }
+            if options.size != 0 {
+                bindings::seq_printf(seq, c_str!(",size=%llu").as_char_ptr(), options.size);
+            }
+            if options.nr_inodes != 0 {
+                let format = c_str!(",nr_inodes=%llu").as_char_ptr();
+                bindings::seq_printf(seq, format, options.nr_inodes);
+            }
+            if options.acl {
+                bindings::seq_printf(seq, c_str!(",acl").as_char_ptr());
+            }
+        }
+        0
+    }
+
+    unsafe extern "C" fn statfs(
+        dentry: *mut bindings::dentry,
+        buf: *mut bindings::kstatfs,
+    ) -> c_int {
+        // SAFETY: The VFS passes a dentry of a mounted `rustfs` and a buffer for the result.
+        unsafe {
+            bindings::simple_statfs(dentry, buf);
+            let info = FsInfo::get((*dentry).d_sb);
+            let buf = &mut *buf;
+            if info.max_pages != 0 {
+                let used = info.used_pages.load(Ordering::Relaxed);
+                buf.f_blocks = info.max_pages;
+                buf.f_bfree = info.max_pages.saturating_sub(used);
+                buf.f_bavail = buf.f_bfree;
+            }
+            if info.options.nr_inodes != 0 {
+                let used = info.used_inodes.load(Ordering::Relaxed);
+                buf.f_files = info.options.nr_inodes;
+                buf.f_ffree = info.options.nr_inodes.saturating_sub(used);
+            }
+        }
+        0
+    }
+
+    unsafe extern "C" fn evict_inode(inode: *mut bindings::inode) {
+        // SAFETY: The VFS passes an inode of a mounted `rustfs` with no other users left.
+        unsafe {
+            bindings::truncate_inode_pages_final(&mut (*inode).i_data);
+            bindings::clear_inode(inode);
+
+            let info = FsInfo::get((*inode).i_sb);
+            info.used_pages
+                .fetch_sub(InodeInfo::free(inode), Ordering::Relaxed);
+            info.used_inodes.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    unsafe extern "C" fn write_iter(
+        iocb: *mut bindings::kiocb,
+        from: *mut bindings::iov_iter,
+    ) -> isize {
+        // SAFETY: The VFS passes a write to an open regular file of a mounted `rustfs`.
+        unsafe {
+            let inode = (*(*iocb).ki_filp).f_inode;
+            let info = FsInfo::get((*inode).i_sb);
+
+            // Appending writes are charged for the size seen here, which a concurrent write may
+            // grow before `generic_file_write_iter` locks the file. `settle` charges the final
+            // size afterwards, even if that exceeds the limit.
+            let pos = if (*iocb).ki_flags & bindings::IOCB_APPEND as c_int != 0 {
+                FsInfo::size(inode)
+            } else {
+                (*iocb).ki_pos as u64
+            };
+            if let Err(e) = info.charge(inode, pos.saturating_add((*from).count as u64)) {
+                return e.to_errno() as isize;
+            }
+
+            let ret = bindings::generic_file_write_iter(iocb, from);
+            info.settle(inode);
+            ret
+        }
+    }
+
+    unsafe extern "C" fn setattr(
+        mnt_userns: *mut bindings::user_namespace,
+        dentry: *mut bindings::dentry,
+        attr: *mut bindings::iattr,
+    ) -> c_int {
+        // SAFETY: The VFS passes a locked inode of a mounted `rustfs`. Only regular files are
+        // resized.
+        unsafe {
+            let inode = (*dentry).d_inode;
+            let info = FsInfo::get((*inode).i_sb);
+            let resize = (*attr).ia_valid & bindings::ATTR_SIZE != 0;
+            if resize {
+                if let Err(e) = info.charge(inode, (*attr).ia_size as u64) {
+                    return e.to_errno();
+                }
+            }
+            let ret = bindings::simple_setattr(mnt_userns, dentry, attr);
+            if resize {
+                info.settle(inode);
+            }
+
+            // The access ACL mirrors the permission bits of the mode.
+            #[cfg(CONFIG_FS_POSIX_ACL)]
+            if ret == 0 && (*attr).ia_valid & bindings::ATTR_MODE != 0 {
+                return bindings::posix_acl_chmod(mnt_userns, inode, (*inode).i_mode);
+            }
+            ret
+        }
+    }
+
+    unsafe extern "C" fn xattr_get(
+        handler: *const bindings::xattr_handler,
+        _dentry: *mut bindings::dentry,
+        inode: *mut bindings::inode,
+        name: *const c_char,
+        buffer: *mut c_void,
+        size: usize,
+    ) -> c_int {
+        // SAFETY: The VFS passes one of the handlers of `FsInfo`, a referenced inode of a mounted
+        // `rustfs`, the name without the prefix of the handler, and a buffer of `size` bytes.
+        unsafe {
+            let prefix = CStr::from_char_ptr((*handler).prefix).as_bytes();
+            let name = CStr::from_char_ptr(name).as_bytes();
+            let xattrs = InodeInfo::of(inode).xattrs.lock();
+            let value = match xattrs.get(prefix, name) {
+                Some(xattr) => &xattr.value,
+                None => return ENODATA.to_errno(),
+            };
+            if size != 0 {
+                if value.len() > size {
+                    return ERANGE.to_errno();
+                }
+                core::ptr::copy_nonoverlapping(value.as_ptr(), buffer as *mut u8, value.len());
+            }
+            value.len() as c_int
+        }
+    }
+
+    unsafe extern "C" fn xattr_set(
+        handler: *const bindings::xattr_handler,
+        _mnt_userns: *mut bindings::user_namespace,
+        _dentry: *mut bindings::dentry,
+        inode: *mut bindings::inode,
+        name: *const c_char,
+        value: *const c_void,
+        size: usize,
+        flags: c_int,
+    ) -> c_int {
+        // SAFETY: The VFS passes one of the handlers of `FsInfo`, a locked inode of a mounted
+        // `rustfs` on which the caller may set the attribute, the name without the prefix of the
+        // handler, and a value of `size` bytes or NULL to remove the attribute.
+        unsafe {
+            let prefix = CStr::from_char_ptr((*handler).prefix).as_bytes();
+            let name = CStr::from_char_ptr(name).as_bytes();
+            let value =
+                (!value.is_null()).then(|| core::slice::from_raw_parts(value as *const u8, size));
+            let ret = InodeInfo::of(inode)
+                .xattrs
+                .lock()
+                .set(prefix, name, value, flags as u32);
+            if ret.is_ok() {
+                (*inode).i_ctime = bindings::current_time(inode);
+            }
+            to_errno(ret)
+        }
+    }
+
+    unsafe extern "C" fn listxattr(
+        dentry: *mut bindings::dentry,
+        buffer: *mut c_char,
+        size: usize,
+    ) -> isize {
+        // SAFETY: The VFS passes a referenced dentry of a mounted `rustfs` and a buffer of `size`
+        // bytes.
+        let inode = unsafe { (*dentry).d_inode };
+        let mut list = XattrList {
+            buf: buffer,
+            size,
+            len: 0,
+        };
+        let ret = (|| -> Result {
+            // SAFETY: The ACLs of the inode are only read to check whether they are set.
+            #[cfg(CONFIG_FS_POSIX_ACL)]
+            unsafe {
+                // Uncached ACLs, such as `ACL_NOT_CACHED`, are odd values, as `is_uncached_acl`
+                // checks.
+                let is_set =
+                    |acl: *mut bindings::posix_acl| !acl.is_null() && acl as usize & 1 == 0;
+                if is_set((*inode).i_acl) {
+                    list.push(b"system.posix_acl_access")?;
+                }
+                if is_set((*inode).i_default_acl) {
+                    list.push(b"system.posix_acl_default")?;
+                }
+            }
+
+            // Trusted attributes are hidden from unprivileged users.
+            // SAFETY: FFI call with no additional requirements.
+            let trusted = unsafe { bindings::capable(bindings::CAP_SYS_ADMIN as c_int) };
+            // SAFETY: The inode is referenced by the dentry.
+            let xattrs = unsafe { &InodeInfo::of(inode).xattrs }.lock();
+            for xattr in &xattrs.list {
+                if trusted || !xattr.name.starts_with(b"trusted.") {
+                    list.push(&xattr.name)?;
+                }
+            }
+            Ok(())
+        })();
+        match ret {
+            Ok(()) => list.len as isize,
+            Err(e) => e.to_errno() as isize,
+        }
     }
 }
 
 impl fs::Type for RustFs {
     type Context = Self;
+    type Data = Box<FsInfo>;
     const SUPER_TYPE: fs::Super = fs::Super::Independent;
     const NAME: &'static CStr = c_str!("rustfs");
     const FLAGS: i32 = fs::flags::USERNS_MOUNT;
 
-    fn fill_super(_data: (), sb: fs::NewSuperBlock<'_, Self>) -> Result<&fs::SuperBlock<Self>> {
+    // The dentries pin the files, so they must be dropped when the file system is unmounted.
+    const DCACHE_BASED: bool = true;
+
+    fn fill_super(
+        data: Box<MountOptions>,
+        sb: fs::NewSuperBlock<'_, Self>,
+    ) -> Result<&fs::SuperBlock<Self>> {
         let sb = sb.init(
-            (),
+            FsInfo::try_new(*data)?,
             &fs::SuperParams {
                 magic: 0x72757374,
                 ..fs::SuperParams::DEFAULT
             },
         )?;
         let sb = sb.init_root()?;
+        let raw = raw_sb(sb);
+
+        // SAFETY: The superblock and its root are initialised, and neither is reachable by
+        // anything else until this returns, so the state can be modified.
+        unsafe {
+            let info = &mut *((*raw).s_fs_info as *mut FsInfo);
+
+            // The ids are mapped in the user namespace of the mount, which may not map them.
+            let uid = bindings::make_kuid((*raw).s_user_ns, info.options.uid);
+            let gid = bindings::make_kgid((*raw).s_user_ns, info.options.gid);
+            if uid.val == INVALID_ID || gid.val == INVALID_ID {
+                return Err(EINVAL);
+            }
+
+            // The default superblock operations neither report the options nor account usage.
+            info.super_ops = core::ptr::read((*raw).s_op);
+            info.super_ops.statfs = Some(Self::statfs);
+            info.super_ops.show_options = Some(Self::show_options);
+            info.super_ops.evict_inode = Some(Self::evict_inode);
+            (*raw).s_op = &info.super_ops;
+
+            #[cfg(CONFIG_FS_POSIX_ACL)]
+            if info.options.acl {
+                (*raw).s_flags |= bindings::SB_POSIXACL as c_ulong;
+                info.xattr_handlers[3] = &bindings::posix_acl_access_xattr_handler;
+                info.xattr_handlers[4] = &bindings::posix_acl_default_xattr_handler;
+            }
+            (*raw).s_xattr = info.xattr_handlers.as_ptr();
+
+            // The root is created with the operations of an empty directory, which do not allow
+            // creating entries.
+            let root = (*(*raw).s_root).d_inode;
+            (*root).i_private = InodeInfo::try_new()? as *mut c_void;
+            (*root).i_op = &info.dir_inode_ops;
+            (*root).i_mode = (bindings::S_IFDIR | info.options.mode) as bindings::umode_t;
+            (*root).i_uid = uid;
+            (*root).i_gid = gid;
+        }
+        Ok(sb)
+    }
+}
+
+/// The magic number of `tarfs` superblocks.
+const TARFS_MAGIC: u32 = 0x74617266;
+
+/// The size of tar headers, to which the contents of entries are also padded.
+const TAR_BLOCK_SIZE: u64 = 512;
+
+/// The maximum size of the contents of extension headers.
+const TAR_EXTENSION_MAX: u64 = 64 * 1024;
+
+/// Returns the components of `path`, skipping empty ones and `.`.
+fn components(path: &[u8]) -> impl Iterator<Item = &[u8]> {
+    path.split(|&b| b == b'/')
+        .filter(|name| !name.is_empty() && *name != b".")
+}
+
+/// Returns `bytes` up to the first NUL byte.
+fn until_nul(bytes: &[u8]) -> &[u8] {
+    bytes.split(|&b| b == 0).next().unwrap_or(bytes)
+}
+
+/// Parses a decimal number, as used in pax extended headers.
+fn parse_decimal(digits: &[u8]) -> Result<u64> {
+    core::str::from_utf8(digits)
+        .map_err(|_| EINVAL)?
+        .parse()
+        .map_err(|_| EINVAL)
+}
+
+/// Copies `bytes` to a new vector.
+fn try_to_vec(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut vec = Vec::new();
+    vec.try_extend_from_slice(bytes)?;
+    Ok(vec)
+}
+
+/// A tar header, in the ustar format.
+struct TarHeader([u8; TAR_BLOCK_SIZE as usize]);
+
+impl TarHeader {
+    /// Returns the field at `offset` of `len` bytes, up to the first NUL byte.
+    fn field(&self, offset: usize, len: usize) -> &[u8] {
+        until_nul(&self.0[offset..offset + len])
+    }
+
+    /// Parses the numeric field at `offset` of `len` bytes.
+    ///
+    /// Numbers are in octal, padded with spaces or NUL bytes, or in base-256 when the high bit of
+    /// the first byte is set, as GNU tar writes numbers too large for octal.
+    fn number(&self, offset: usize, len: usize) -> Result<u64> {
+        let field = &self.0[offset..offset + len];
+        if field[0] & 0x80 != 0 {
+            return field[1..]
+                .iter()
+                .try_fold(u64::from(field[0] & 0x7f), |n, &b| {
+                    n.checked_mul(256)
+                        .and_then(|n| n.checked_add(u64::from(b)))
+                        .ok_or(EINVAL)
+                });
+        }
+        field
+            .iter()
+            .skip_while(|&&b| b == b' ')
+            .take_while(|&&b| b != b' ' && b != 0)
+            .try_fold(0u64, |n, &b| match b {
+                b'0'..=b'7' => n
+                    .checked_mul(8)
+                    .map(|n| n + u64::from(b - b'0'))
+                    .ok_or(EINVAL),
+                _ => Err(EINVAL),
+            })
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0.iter().all(|&b| b == 0)
+    }
+
+    /// Checks the checksum, the sum of the bytes of the header with the checksum field counted as
+    /// spaces.
+    fn is_valid(&self) -> bool {
+        let sum = self
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b })
+            .fold(0u64, |sum, b| sum + u64::from(b));
+        self.number(148, 8) == Ok(sum)
+    }
+
+    fn kind(&self) -> u8 {
+        self.0[156]
+    }
+
+    /// Returns the path, joining the prefix and the name for ustar archives.
+    fn path(&self) -> Result<Vec<u8>> {
+        let mut path = Vec::new();
+        let prefix = self.field(345, 155);
+        if &self.0[257..262] == b"ustar" && !prefix.is_empty() {
+            path.try_extend_from_slice(prefix)?;
+            path.try_push(b'/')?;
+        }
+        path.try_extend_from_slice(self.field(0, 100))?;
+        Ok(path)
+    }
+}
+
+/// The values set by extension headers for the next entry.
+#[derive(Default)]
+struct TarExtensions {
+    path: Option<Vec<u8>>,
+    link: Option<Vec<u8>>,
+    size: Option<u64>,
+    mtime: Option<u64>,
+}
+
+impl TarExtensions {
+    /// Applies the records of a pax extended header, each formatted as `LENGTH KEY=VALUE\n`.
+    fn parse_pax(&mut self, mut data: &[u8]) -> Result {
+        while !data.is_empty() {
+            let space = data.iter().position(|&b| b == b' ').ok_or(EINVAL)?;
+            let len = usize::try_from(parse_decimal(&data[..space])?).map_err(|_| EINVAL)?;
+            if len <= space + 1 || len > data.len() || data[len - 1] != b'\n' {
+                return Err(EINVAL);
+            }
+            let record = &data[space + 1..len - 1];
+            let equals = record.iter().position(|&b| b == b'=').ok_or(EINVAL)?;
+            let value = &record[equals + 1..];
+            match &record[..equals] {
+                b"path" => self.path = Some(try_to_vec(value)?),
+                b"linkpath" => self.link = Some(try_to_vec(value)?),
+                b"size" => self.size = Some(parse_decimal(value)?),
+                // Fractions of seconds are ignored, as are times before the epoch.
+                b"mtime" => {
+                    let seconds = value.split(|&b| b == b'.').next().unwrap_or(value);
+                    self.mtime = parse_decimal(seconds).ok();
+                }
+                _ => {}
+            }
+            data = &data[len..];
+        }
+        Ok(())
+    }
+}
+
+/// An entry of a tar archive, once the extension headers that precede it are applied.
+struct TarEntry {
+    path: Vec<u8>,
+    kind: u8,
+    link: Vec<u8>,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u64,
+    size: u64,
+    rdev: bindings::dev_t,
+
+    /// The offset of the contents in the image.
+    offset: u64,
+}
+
+/// An open tar image, closed when dropped.
+struct TarImage(*mut bindings::file);
+
+impl TarImage {
+    fn open(path: &CStr) -> Result<Self> {
+        let flags = (file::flags::O_RDONLY | bindings::O_LARGEFILE) as c_int;
+        // SAFETY: `path` is a valid NUL-terminated string.
+        let file = from_err_ptr(unsafe { bindings::filp_open(path.as_char_ptr(), flags, 0) })?;
+        let image = Self(file);
+
+        // SAFETY: An open file holds a reference to its inode.
+        let mode = u32::from(unsafe { (*(*file).f_inode).i_mode }) & bindings::S_IFMT;
+        if mode != bindings::S_IFREG && mode != bindings::S_IFBLK {
+            return Err(EINVAL);
+        }
+        Ok(image)
+    }
+
+    /// Reads from `pos` into `buf`, and returns the number of bytes read, which is zero at the
+    /// end of the image.
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> Result<usize> {
+        let mut pos = pos as bindings::loff_t;
+        // SAFETY: The file is open, and `buf` is valid for writes of its length.
+        let ret = unsafe {
+            bindings::kernel_read(self.0, buf.as_mut_ptr() as *mut c_void, buf.len(), &mut pos)
+        };
+        if ret < 0 {
+            to_result(ret as c_int)?;
+        }
+        Ok(ret as usize)
+    }
+
+    /// Fills `buf` from `pos`, failing with `EIO` if the image is too short.
+    fn read_exact(&self, buf: &mut [u8], pos: u64) -> Result {
+        let mut done = 0;
+        while done < buf.len() {
+            match self.read_at(&mut buf[done..], pos + done as u64)? {
+                0 => return Err(EIO),
+                n => done += n,
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the contents of an extension header, of `size` bytes at `pos`.
+    fn read_extension(&self, pos: u64, size: u64) -> Result<Vec<u8>> {
+        if size > TAR_EXTENSION_MAX {
+            return Err(EINVAL);
+        }
+        let mut data = Vec::new();
+        data.try_resize(size as usize, 0)?;
+        self.read_exact(&mut data, pos)?;
+        Ok(data)
+    }
+}
+
+impl Drop for TarImage {
+    fn drop(&mut self) {
+        // SAFETY: The file is open, and is not used after this.
+        unsafe { bindings::filp_close(self.0, core::ptr::null_mut()) };
+    }
+}
+
+/// The state of a mounted `tarfs`.
+struct TarInfo {
+    image: TarImage,
+    file_aops: bindings::address_space_operations,
+
+    /// The offsets of the contents of regular files in the image, indexed by `inode::i_private`.
+    offsets: Vec<u64>,
+
+    /// The NUL-terminated targets of symbolic links, to which `inode::i_link` points.
+    links: Vec<Vec<u8>>,
+}
+
+// SAFETY: The image and the address space operations are only read after the superblock is set
+// up, from any thread.
+unsafe impl Send for TarInfo {}
+
+// SAFETY: `TarInfo` is only modified before the superblock is set up.
+unsafe impl Sync for TarInfo {}
+
+impl TarInfo {
+    fn try_new(image: TarImage) -> Result<Box<Self>> {
+        let mut info = Box::try_new(Self {
+            image,
+            // SAFETY: All fields of `address_space_operations` are optional function pointers, for
+            // which zero is a valid value.
+            file_aops: unsafe { core::mem::zeroed() },
+            offsets: Vec::new(),
+            links: Vec::new(),
+        })?;
+        info.file_aops.read_folio = Some(TarFs::read_folio);
+        Ok(info)
+    }
+
+    /// Returns the [`TarInfo`] of `sb`.
+    ///
+    /// # Safety
+    ///
+    /// `sb` must be a valid `tarfs` superblock.
+    unsafe fn get<'a>(sb: *mut bindings::super_block) -> &'a Self {
+        // SAFETY: `s_fs_info` is the `TarInfo` passed to `NewSuperBlock::init`, which is freed
+        // with the superblock.
+        unsafe { &*((*sb).s_fs_info as *const Self) }
+    }
+
+    /// Fills `buf` from `pos` of a file of `size` bytes at `offset` in the image, with zeroes
+    /// past its end.
+    fn read(&self, offset: u64, size: u64, pos: u64, buf: &mut [u8]) -> Result {
+        let len = min(buf.len() as u64, size.saturating_sub(pos)) as usize;
+        self.image.read_exact(&mut buf[..len], offset + pos)?;
+        buf[len..].fill(0);
+        Ok(())
+    }
+}
+
+/// A reference to a dentry, released when dropped.
+struct DentryRef(*mut bindings::dentry);
+
+impl DentryRef {
+    fn inode(&self) -> *mut bindings::inode {
+        // SAFETY: The reference keeps the dentry alive.
+        unsafe { (*self.0).d_inode }
+    }
+
+    /// Returns whether the dentry, which must be positive, is a directory.
+    fn is_dir(&self) -> bool {
+        // SAFETY: The dentry is referenced and positive, so its inode is alive.
+        u32::from(unsafe { (*self.inode()).i_mode }) & bindings::S_IFMT == bindings::S_IFDIR
+    }
+}
+
+impl Drop for DentryRef {
+    fn drop(&mut self) {
+        // SAFETY: The reference is owned.
+        unsafe { bindings::dput(self.0) };
+    }
+}
+
+/// Fills a new `tarfs` superblock with the entries of its image.
+///
+/// The dcache is the only index of the tree: entries are found with the usual lookups, and new
+/// dentries are pinned with an extra reference, as in `rustfs`, until the superblock is killed.
+struct TarBuilder<'a> {
+    info: &'a mut TarInfo,
+    sb: *mut bindings::super_block,
+}
+
+impl TarBuilder<'_> {
+    /// Reads all entries of the image.
+    fn run(&mut self) -> Result {
+        let mut header = TarHeader([0; TAR_BLOCK_SIZE as usize]);
+        let mut extensions = TarExtensions::default();
+        let mut pos = 0;
+        loop {
+            // Archives end with zeroed blocks, but some are truncated after the last entry.
+            if self.info.image.read_at(&mut header.0[..1], pos)? == 0 {
+                return Ok(());
+            }
+            self.info.image.read_exact(&mut header.0, pos)?;
+            if header.is_zero() {
+                return Ok(());
+            }
+            if !header.is_valid() {
+                return Err(EINVAL);
+            }
+
+            let offset = pos + TAR_BLOCK_SIZE;
+            let size = extensions
+                .size
+                .take()
+                .map_or_else(|| header.number(124, 12), Ok)?;
+            let padded =
+                size.checked_add(TAR_BLOCK_SIZE - 1).ok_or(EINVAL)? & !(TAR_BLOCK_SIZE - 1);
+            pos = offset.checked_add(padded).ok_or(EINVAL)?;
+
+            match header.kind() {
+                b'L' => {
+                    let data = self.info.image.read_extension(offset, size)?;
+                    extensions.path = Some(try_to_vec(until_nul(&data))?);
+                }
+                b'K' => {
+                    let data = self.info.image.read_extension(offset, size)?;
+                    extensions.link = Some(try_to_vec(until_nul(&data))?);
+                }
+                b'x' => extensions.parse_pax(&self.info.image.read_extension(offset, size)?)?,
+                // Global pax headers and other extensions only carry metadata.
+                b'g' | b'A'..=b'Z' => {}
+                kind => {
+                    let path = match extensions.path.take() {
+                        Some(path) => path,
+                        None => header.path()?,
+                    };
+                    let link = match extensions.link.take() {
+                        Some(link) => link,
+                        None => try_to_vec(header.field(157, 100))?,
+                    };
+                    let mtime = match extensions.mtime.take() {
+                        Some(mtime) => mtime,
+                        None => header.number(136, 12)?,
+                    };
+                    // Only device nodes have a meaningful device number, which must fit in a
+                    // `dev_t`: 12 bits for the major, and `MINORBITS` for the minor.
+                    let (mut major, mut minor) = (0, 0);
+                    if kind == b'3' || kind == b'4' {
+                        major = header.number(329, 8)?;
+                        minor = header.number(337, 8)?;
+                        if major >= 1 << 12 || minor >= 1 << bindings::MINORBITS {
+                            return Err(EINVAL);
+                        }
+                    }
+                    self.add(&TarEntry {
+                        // Before ustar, directories were regular files with a trailing slash.
+                        kind: if kind == b'0' && path.ends_with(b"/") {
+                            b'5'
+                        } else {
+                            kind
+                        },
+                        path,
+                        link,
+                        mode: header.number(100, 8)? as u32 & bindings::S_IALLUGO,
+                        uid: header.number(108, 8)? as u32,
+                        gid: header.number(116, 8)? as u32,
+                        mtime,
+                        size,
+                        rdev: (major << bindings::MINORBITS | minor) as bindings::dev_t,
+                        offset,
+                    })?;
+                    extensions = TarExtensions::default();
+                }
+            }
+        }
+    }
+
+    /// Returns the type of inode for entries of `kind`, or `None` for unsupported ones.
+    fn file_type(kind: u8) -> Option<u32> {
+        match kind {
+            b'0' | 0 | b'7' => Some(bindings::S_IFREG),
+            b'2' => Some(bindings::S_IFLNK),
+            b'3' => Some(bindings::S_IFCHR),
+            b'4' => Some(bindings::S_IFBLK),
+            b'5' => Some(bindings::S_IFDIR),
+            b'6' => Some(bindings::S_IFIFO),
+            _ => None,
+        }
+    }
+
+    /// Looks up `name` in `dir`, returning a negative dentry if it does not exist.
+    fn lookup(dir: &DentryRef, name: &[u8]) -> Result<DentryRef> {
+        if name == b".." {
+            return Err(EINVAL);
+        }
+        let len = c_int::try_from(name.len()).map_err(|_| ENAMETOOLONG)?;
+        // SAFETY: `dir` is a referenced directory, and `name` is valid for reads of `len` bytes.
+        let dentry = from_err_ptr(unsafe {
+            bindings::lookup_one_len_unlocked(name.as_ptr() as *const _, dir.0, len)
+        })?;
+        Ok(DentryRef(dentry))
+    }
+
+    /// Returns the entry at `path`, which must exist.
+    fn resolve(&self, path: &[u8]) -> Result<DentryRef> {
+        // SAFETY: The root is set by `init_root`.
+        let mut dentry = DentryRef(unsafe { bindings::dget((*self.sb).s_root) });
+        for name in components(path) {
+            // Only directories can be looked up in, others have no `lookup` operation.
+            if !dentry.is_dir() {
+                return Err(ENOTDIR);
+            }
+            dentry = Self::lookup(&dentry, name)?;
+            if dentry.inode().is_null() {
+                return Err(ENOENT);
+            }
+        }
+        Ok(dentry)
+    }
+
+    /// Adds `entry`, creating the directories leading to it if the archive does not list them.
+    fn add(&mut self, entry: &TarEntry) -> Result {
+        // SAFETY: The root is set by `init_root`.
+        let mut dir = DentryRef(unsafe { bindings::dget((*self.sb).s_root) });
+        let mut names = components(&entry.path).peekable();
+        while let Some(name) = names.next() {
+            if names.peek().is_none() {
+                return self.add_at(&dir, name, entry);
+            }
+            let dentry = Self::lookup(&dir, name)?;
+            if dentry.inode().is_null() {
+                // The directory gets the owner and time of the entry until its own is found.
+                let mode = bindings::S_IFDIR | 0o755;
+                let inode = self.new_inode(entry, mode)?;
+                self.instantiate(&dir, &dentry, inode);
+            } else if !dentry.is_dir() {
+                return Err(ENOTDIR);
+            }
+            dir = dentry;
+        }
+
+        // The entry is the root.
+        if entry.kind == b'5' {
+            self.update(dir.inode(), entry, bindings::S_IFDIR | entry.mode);
+        }
+        Ok(())
+    }
+
+    /// Adds `entry` as `name` in `dir`, replacing the entry already there, as extracting the
+    /// archive would.
+    fn add_at(&mut self, dir: &DentryRef, name: &[u8], entry: &TarEntry) -> Result {
+        // The target is resolved first, as it may be the entry being replaced.
+        let target = match entry.kind {
+            b'1' => match self.resolve(&entry.link) {
+                Ok(target) if !target.is_dir() => Some(target),
+                Ok(_) => return Self::skip_link(EPERM),
+                Err(e) if e == ENOMEM => return Err(e),
+                Err(e) => return Self::skip_link(e),
+            },
+            _ => None,
+        };
+
+        let mut dentry = Self::lookup(dir, name)?;
+        if !dentry.inode().is_null() {
+            if dentry.is_dir() {
+                // Directories may have been created before their entry is found, and are kept
+                // along with their contents.
+                if entry.kind == b'5' {
+                    self.update(dentry.inode(), entry, bindings::S_IFDIR | entry.mode);
+                } else {
+                    pr_warn!("tarfs: ignoring entry replacing a directory\n");
+                }
+                return Ok(());
+            }
+
+            // SAFETY: `dentry` is a referenced positive dentry in `dir`, whose pin is released as
+            // `simple_unlink` does. The inode stays alive until the dentry is freed.
+            unsafe {
+                bindings::drop_nlink(dentry.inode());
+                bindings::d_delete(dentry.0);
+                bindings::dput(dentry.0);
+            }
+            // The old dentry is unhashed, so this allocates a new negative one.
+            dentry = Self::lookup(dir, name)?;
+        }
+
+        if let Some(target) = target {
+            let inode = target.inode();
+            // SAFETY: The new dentry holds the reference taken here.
+            unsafe {
+                bindings::ihold(inode);
+                bindings::inc_nlink(inode);
+            }
+            self.instantiate(dir, &dentry, inode);
+            return Ok(());
+        }
+
+        match Self::file_type(entry.kind) {
+            Some(file_type) => {
+                let inode = self.new_inode(entry, file_type | entry.mode)?;
+                self.instantiate(dir, &dentry, inode);
+            }
+            None => pr_warn!("tarfs: ignoring entry of unsupported type {}\n", entry.kind),
+        }
+        Ok(())
+    }
+
+    /// Skips a hard link whose target cannot be linked to, with the error `e`.
+    fn skip_link(e: Error) -> Result {
+        pr_warn!(
+            "tarfs: ignoring hard link to a missing entry or directory: {:?}\n",
+            e
+        );
+        Ok(())
+    }
+
+    /// Creates an inode of `mode` for `entry`.
+    fn new_inode(&mut self, entry: &TarEntry, mode: u32) -> Result<*mut bindings::inode> {
+        // The contents are recorded before the inode is created, so that it can be freed by simply
+        // dropping it.
+        let file_type = mode & bindings::S_IFMT;
+        let mut link_ptr = core::ptr::null_mut();
+        if file_type == bindings::S_IFREG {
+            self.info.offsets.try_push(entry.offset)?;
+        } else if file_type == bindings::S_IFLNK {
+            let mut link = Vec::new();
+            link.try_extend_from_slice(&entry.link)?;
+            link.try_push(0)?;
+            // Moving the vector does not move its buffer, which the inode points to.
+            link_ptr = link.as_mut_ptr();
+            self.info.links.try_push(link)?;
+        }
+
+        // SAFETY: The superblock is valid.
+        let inode = unsafe { bindings::new_inode(self.sb) };
+        if inode.is_null() {
+            return Err(ENOMEM);
+        }
+
+        // SAFETY: `inode` was just allocated, so nothing else uses it yet.
+        unsafe {
+            (*inode).i_ino = bindings::get_next_ino().into();
+            match file_type {
+                bindings::S_IFREG => {
+                    (*inode).i_fop = &bindings::generic_ro_fops;
+                    (*(*inode).i_mapping).a_ops = &self.info.file_aops;
+                    (*inode).i_size = entry.size as bindings::loff_t;
+                    (*inode).i_private = (self.info.offsets.len() - 1) as *mut c_void;
+                }
+                bindings::S_IFLNK => {
+                    (*inode).i_op = &bindings::simple_symlink_inode_operations;
+                    (*inode).i_link = link_ptr as *mut _;
+                    (*inode).i_size = entry.link.len() as bindings::loff_t;
+                }
+                bindings::S_IFDIR => {
+                    (*inode).i_op = &bindings::simple_dir_inode_operations;
+                    (*inode).i_fop = &bindings::simple_dir_operations;
+                    bindings::inc_nlink(inode);
+                }
+                _ => bindings::init_special_inode(inode, mode as bindings::umode_t, entry.rdev),
+            }
+        }
+        self.update(inode, entry, mode);
+        Ok(inode)
+    }
+
+    /// Sets the mode of `inode`, and its owner and times from `entry`.
+    fn update(&self, inode: *mut bindings::inode, entry: &TarEntry, mode: u32) {
+        // SAFETY: The inode is valid, and nothing else uses it while the superblock is set up.
+        unsafe {
+            (*inode).i_mode = mode as bindings::umode_t;
+            (*inode).i_uid = bindings::make_kuid((*self.sb).s_user_ns, entry.uid);
+            (*inode).i_gid = bindings::make_kgid((*self.sb).s_user_ns, entry.gid);
+            let time = bindings::timespec64 {
+                tv_sec: entry.mtime.try_into().unwrap_or(i64::MAX),
+                tv_nsec: 0,
+            };
+            (*inode).i_atime = time;
+            (*inode).i_mtime = time;
+            (*inode).i_ctime = time;
+        }
+    }
+
+    /// Makes `dentry` in `dir` refer to `inode`, whose reference it takes over.
+    fn instantiate(&self, dir: &DentryRef, dentry: &DentryRef, inode: *mut bindings::inode) {
+        // SAFETY: `dentry` is a referenced negative dentry, and `dir` its referenced parent.
+        unsafe {
+            bindings::d_instantiate(dentry.0, inode);
+            // Pins the dentry, and therefore the inode, until the superblock is killed.
+            bindings::dget(dentry.0);
+            if u32::from((*inode).i_mode) & bindings::S_IFMT == bindings::S_IFDIR {
+                bindings::inc_nlink(dir.inode());
+            }
+        }
+    }
+}
+
+/// The options of a `tarfs` mount.
+struct TarOptions {
+    /// The path of the image.
+    source: Option<CString>,
+}
+
+struct TarFs;
+
+#[vtable]
+impl fs::Context<Self> for TarFs {
+    type Data = Box<TarOptions>;
+
+    kernel::define_fs_params! {Box<TarOptions>,
+        {string, "source", |o, v| {
+            o.source = Some(CString::try_from_fmt(fmt!("{v}"))?);
+            Ok(())
+        } },
+    }
+
+    fn try_new() -> Result<Self::Data> {
+        Ok(Box::try_new(TarOptions { source: None })?)
+    }
+}
+
+impl TarFs {
+    unsafe extern "C" fn read_folio(
+        _file: *mut bindings::file,
+        folio: *mut bindings::folio,
+    ) -> c_int {
+        // SAFETY: The VFS passes a locked folio of a regular file of a mounted `tarfs`, which is
+        // only unlocked here. The file system does not enable large folios, so the folio is a
+        // single page.
+        unsafe {
+            let inode = (*bindings::folio_mapping(folio)).host;
+            let info = TarInfo::get((*inode).i_sb);
+            let offset = info.offsets[(*inode).i_private as usize];
+            let addr = bindings::kmap_local_folio(folio, 0) as *mut u8;
+            let buf = core::slice::from_raw_parts_mut(addr, kernel::PAGE_SIZE);
+            let result = info.read(
+                offset,
+                (*inode).i_size as u64,
+                bindings::folio_pos(folio) as u64,
+                buf,
+            );
+            bindings::kunmap_local(addr as *const c_void);
+            let ret = match result {
+                Ok(()) => {
+                    bindings::folio_mark_uptodate(folio);
+                    0
+                }
+                Err(e) => e.to_errno(),
+            };
+            bindings::folio_unlock(folio);
+            ret
+        }
+    }
+}
+
+impl fs::Type for TarFs {
+    type Context = Self;
+    type Data = Box<TarInfo>;
+    const SUPER_TYPE: fs::Super = fs::Super::Independent;
+    const NAME: &'static CStr = c_str!("tarfs");
+    const FLAGS: i32 = 0;
+
+    // The dentries pin the entries, so they must be dropped when the file system is unmounted.
+    const DCACHE_BASED: bool = true;
+
+    fn fill_super(
+        data: Box<TarOptions>,
+        sb: fs::NewSuperBlock<'_, Self>,
+    ) -> Result<&fs::SuperBlock<Self>> {
+        let image = TarImage::open(data.source.as_deref().ok_or(EINVAL)?)?;
+        let sb = sb.init(
+            TarInfo::try_new(image)?,
+            &fs::SuperParams {
+                magic: TARFS_MAGIC,
+                ..fs::SuperParams::DEFAULT
+            },
+        )?;
+        let sb = sb.init_root()?;
+        let raw = raw_sb(sb);
+
+        // SAFETY: The superblock and its root are initialised, and neither is reachable by
+        // anything else until this returns, so the state can be modified.
+        unsafe {
+            (*raw).s_flags |= bindings::SB_RDONLY as c_ulong;
+            TarBuilder {
+                info: &mut *((*raw).s_fs_info as *mut TarInfo),
+                sb: raw,
+            }
+            .run()?;
+        }
         Ok(sb)
     }
 }
@@ -1,10 +1,87 @@
 // SPDX-License-Identifier: GPL-2.0
 
 //! Rust netfilter sample.
+//!
+//! Counts the packets and bytes seen at the hooks selected by the `families` (`ipv4`, `ipv6`,
+//! `arp` and `bridge`) and `hooks` (`prerouting`, `input`, `forward`, `output` and `postrouting`)
+//! parameters, optionally logging a ratelimited sample of them with their IP and transport headers
+//! decoded.
+//!
+//! The hooks are registered in every network namespace, including the ones created after the
+//! module is loaded, and unregistered from each namespace when it is destroyed. Loading fails if
+//! they cannot be registered in an existing namespace, whereas a namespace created later in which
+//! they cannot be is only left without them, with a warning. Counters, rules, flows and the
+//! capture are shared by all namespaces. Their priority is that of the built-in hook named by
+//! `priority_base` plus the `priority` offset, e.g., `priority_base=conntrack priority=1` sees
+//! packets right after connection tracking. The bridge family only has the `first`, `nat_dst`,
+//! `filter`, `nat_src` and `last` bases.
+//!
+//! IP packets are accepted, dropped or queued to userspace according to a rule table loaded through
+//! the `/dev/rust_netfilter` misc device, one rule per line:
+//!
+//! ```text
+//! accept|drop|queue NUM [bypass] [in|forward|out] [src ADDR[/LEN]] [dst ADDR[/LEN]]
+//!             [proto tcp|udp|icmp|icmpv6|NUM] [sport PORT[-PORT]] [dport PORT[-PORT]]
+//!             [state STATE[,STATE...]] [mark VALUE[/MASK]] [setmark VALUE] [limit]
+//! ```
+//!
+//! `state` matches the connection tracking state of the packet: `new`, `established`, `related`,
+//! `untracked`, or `invalid` for packets that conntrack could not track. The `track_connections`
+//! parameter enables connection tracking in every namespace, which `state` rules need unless
+//! something else enables it. It is off by default, as every connection is then tracked. The
+//! state is only known to hooks with a priority after `conntrack`, which the default one is. For
+//! instance, `drop in proto tcp dport 23 state new` refuses new telnet connections.
+//!
+//! `mark` matches the packet mark, masked with `MASK` if given, and `setmark` sets the mark of the
+//! matching packets, e.g., for `ip rule add fwmark` policy routing, which only sees the marks set
+//! at the `prerouting` hook.
+//!
+//! `limit` drops the matching packets that exceed the rate allowed to their source address, which
+//! is `limit_rate` packets per second with bursts of up to `limit_burst` packets, e.g.,
+//! `accept in proto tcp dport 8080 limit`. Up to `limit_max_sources` sources are tracked at once.
+//! Once that many are, the least recently seen one is forgotten to make room for a new one, and
+//! while any are, the sources that stayed within their rate long enough to be forgotten are
+//! periodically removed.
+//!
+//! Packets matching a `queue` rule are sent to the given NFQUEUE number, where a userspace program
+//! such as the `nfqueue` host program sample decides their fate. They are dropped if no program is
+//! listening on the queue, unless the rule has the `bypass` option, in which case they are
+//! accepted.
+//!
+//! The direction is `in` for the `prerouting` and `input` hooks, `forward` for the `forward` hook
+//! and `out` for the `output` and `postrouting` hooks. The first matching rule decides, and
+//! packets matching no rule are accepted. Everything after a `#` is a comment. The rules written
+//! through an open file replace the whole table when it is closed, so `cat rules >
+//! /dev/rust_netfilter` loads a table and `: > /dev/rust_netfilter` clears it. Reading the device
+//! lists the rules in the same format, with their hit counters in a comment.
+//!
+//! Packets and bytes are also accounted per flow, identified by addresses, protocol and ports, in
+//! a table of bounded size that evicts the least recently seen flows. The flows that transferred
+//! the most bytes are listed in `rust_netfilter/top_talkers` in debugfs. Like the per-hook
+//! counters, flows are accounted at every hook, so forwarded packets are counted twice.
+//!
+//! When the `capture_packets` parameter is set, the IP packets seen by the hooks while
+//! `/dev/rust_netfilter_pcap` is open are made available by reading it in pcap format, e.g., with
+//! `tcpdump -r /dev/rust_netfilter_pcap`. Packets that do not fit in the buffer because the
+//! reader is too slow are dropped from the capture, and counted.
 
+use core::ffi::{c_int, c_uint, c_void};
+use core::fmt::{self, Write};
+use core::future::{self, Future};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use core::task::{Poll, Waker};
+use core::time::Duration;
+use kernel::bindings;
+use kernel::error::to_result;
+use kernel::file::{self, File};
+use kernel::io_buffer::{IoBufferReader, IoBufferWriter};
+use kernel::kasync::executor::{workqueue::Executor as WqExecutor, AutoStopHandle, Executor};
+use kernel::kasync::time::sleep;
+use kernel::miscdev;
 use kernel::net;
-use kernel::net::filter::{self as netfilter, inet, Disposition, Family};
 use kernel::prelude::*;
+use kernel::sync::{rcu, Arc, ArcBorrow, CondVar, Mutex, SpinLock, UniqueArc};
+use kernel::{c_str, condvar_init, mutex_init, spawn_task, spinlock_init, Opaque};
 
 module! {
     type: RustNetfilter,
@@ -12,43 +89,2802 @@ module! {
     author: "Rust for Linux Contributors",
     description: "Rust netfilter sample",
     license: "GPL",
+    params: {
+        families: str {
+            default: b"ipv4,ipv6",
+            permissions: 0o444,
+            description: "Comma-separated families to hook: ipv4, ipv6, arp, bridge",
+        },
+        hooks: str {
+            default: b"prerouting,postrouting",
+            permissions: 0o444,
+            description: "Comma-separated hooks: prerouting, input, forward, output, postrouting",
+        },
+        priority_base: str {
+            default: b"filter",
+            permissions: 0o444,
+            description: "Priority base: first, raw, conntrack, mangle, nat_dst, filter, security, \
+                nat_src or last",
+        },
+        priority: i32 {
+            default: 0,
+            permissions: 0o444,
+            description: "Priority of the hooks relative to priority_base (lower runs earlier)",
+        },
+        log_packets: bool {
+            default: true,
+            permissions: 0o444,
+            description: "Log packets seen by the hooks (counters are kept regardless)",
+        },
+        log_sample: u32 {
+            default: 1,
+            permissions: 0o444,
+            description: "Log only one in every N packets of each hook",
+        },
+        log_burst: u32 {
+            default: 10,
+            permissions: 0o444,
+            description: "Maximum number of packets logged per interval by each hook",
+        },
+        log_interval_ms: u32 {
+            default: 5000,
+            permissions: 0o444,
+            description: "Length of the logging ratelimit interval, in milliseconds",
+        },
+        max_flows: u32 {
+            default: 4096,
+            permissions: 0o444,
+            description: "Maximum number of flows accounted at once (0 to disable)",
+        },
+        top_flows: u32 {
+            default: 10,
+            permissions: 0o444,
+            description: "Number of flows listed in the top talkers report",
+        },
+        track_connections: bool {
+            default: false,
+            permissions: 0o444,
+            description: "Enable connection tracking so that rules can match the connection state",
+        },
+        limit_rate: u32 {
+            default: 10,
+            permissions: 0o444,
+            description: "Packets per second accepted from each source by limit rules",
+        },
+        limit_burst: u32 {
+            default: 20,
+            permissions: 0o444,
+            description: "Packets accepted at once from each source by limit rules",
+        },
+        limit_max_sources: u32 {
+            default: 1024,
+            permissions: 0o444,
+            description: "Maximum number of sources tracked at once by limit rules",
+        },
+        capture_packets: bool {
+            default: false,
+            permissions: 0o444,
+            description: "Provide the packets seen by the hooks in /dev/rust_netfilter_pcap",
+        },
+        capture_snaplen: u32 {
+            default: 65535,
+            permissions: 0o444,
+            description: "Maximum number of bytes captured per packet",
+        },
+        capture_buffer_size: u32 {
+            default: 1048576,
+            permissions: 0o444,
+            description: "Size of the capture buffer, in bytes",
+        },
+    },
 }
 
-struct RustNetfilter {
-    _in: Pin<Box<netfilter::Registration<Self>>>,
-    _out: Pin<Box<netfilter::Registration<Self>>>,
+/// Returns the current monotonic time in nanoseconds.
+fn now_ns() -> i64 {
+    // SAFETY: FFI call with no additional requirements.
+    unsafe { bindings::ktime_get() }
+}
+
+/// Returns the FNV-1a hash of the concatenation of `parts`.
+fn fnv1a(parts: &[&[u8]]) -> u32 {
+    let mut hash = 0x811c9dc5u32;
+    for &b in parts.iter().copied().flatten() {
+        hash = (hash ^ u32::from(b)).wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+const IPPROTO_HOPOPTS: u8 = 0;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ROUTING: u8 = 43;
+const IPPROTO_FRAGMENT: u8 = 44;
+const IPPROTO_AH: u8 = 51;
+const IPPROTO_ICMPV6: u8 = 58;
+const IPPROTO_DSTOPTS: u8 = 60;
+
+/// The maximum number of IPv6 extension headers skipped to find the transport header.
+const MAX_IPV6_EXT_HEADERS: usize = 8;
+
+/// Returns the `sk_buff` wrapped by `skb`.
+fn raw_skb(skb: &net::SkBuff) -> *mut bindings::sk_buff {
+    // `SkBuff` is a transparent wrapper around `bindings::sk_buff`.
+    skb as *const net::SkBuff as *mut bindings::sk_buff
+}
+
+/// The connection tracking state of a packet.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ConnState {
+    /// The packet could not be tracked, or conntrack has not seen it yet.
+    Invalid,
+    New,
+    Established,
+    Related,
+    /// Conntrack was told not to track the packet.
+    Untracked,
+}
+
+impl ConnState {
+    const NAMES: [(&'static str, Self); 5] = [
+        ("invalid", Self::Invalid),
+        ("new", Self::New),
+        ("established", Self::Established),
+        ("related", Self::Related),
+        ("untracked", Self::Untracked),
+    ];
+
+    /// Returns the bit of the state in the sets of states matched by rules.
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl fmt::Display for ConnState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(name_of(&ConnState::NAMES, self))
+    }
+}
+
+/// Accessors for the `sk_buff` fields that `SkBuff` does not expose.
+trait SkBuffExt {
+    /// Returns the link layer protocol of the packet, in host byte order.
+    fn protocol(&self) -> u16;
+
+    /// Returns the mark of the packet.
+    fn mark(&self) -> u32;
+
+    /// Sets the mark of the packet, as the `MARK` target does.
+    fn set_mark(&self, mark: u32);
+
+    /// Returns the connection tracking state of the packet.
+    fn conn_state(&self) -> ConnState;
+}
+
+impl SkBuffExt for net::SkBuff {
+    fn protocol(&self) -> u16 {
+        // SAFETY: `raw_skb` returns a valid pointer.
+        u16::from_be(unsafe { (*raw_skb(self)).protocol })
+    }
+
+    fn mark(&self) -> u32 {
+        // SAFETY: `raw_skb` returns a valid pointer.
+        unsafe { (*raw_skb(self)).mark }
+    }
+
+    fn set_mark(&self, mark: u32) {
+        // SAFETY: `raw_skb` returns a valid pointer, and the hook that is given a packet may
+        // modify its mark.
+        unsafe { (*raw_skb(self)).mark = mark };
+    }
+
+    #[cfg(CONFIG_NF_CONNTRACK)]
+    fn conn_state(&self) -> ConnState {
+        // SAFETY: `raw_skb` returns a valid pointer.
+        let nfct = unsafe { (*raw_skb(self))._nfct };
+        if nfct == 0 {
+            return ConnState::Invalid;
+        }
+        // The low bits of `_nfct` are the `enum ip_conntrack_info` of the packet.
+        let info = nfct & core::ffi::c_ulong::from(bindings::NFCT_INFOMASK);
+        match info as bindings::ip_conntrack_info {
+            bindings::ip_conntrack_info_IP_CT_NEW => ConnState::New,
+            bindings::ip_conntrack_info_IP_CT_ESTABLISHED
+            | bindings::ip_conntrack_info_IP_CT_ESTABLISHED_REPLY => ConnState::Established,
+            bindings::ip_conntrack_info_IP_CT_RELATED
+            | bindings::ip_conntrack_info_IP_CT_RELATED_REPLY => ConnState::Related,
+            bindings::ip_conntrack_info_IP_CT_UNTRACKED => ConnState::Untracked,
+            _ => ConnState::Invalid,
+        }
+    }
+
+    #[cfg(not(CONFIG_NF_CONNTRACK))]
+    fn conn_state(&self) -> ConnState {
+        ConnState::Invalid
+    }
+}
+
+/// Copies the packet data at `offset` into `buf`. The data starts at the network header in the
+/// hooks this sample registers.
+///
+/// Bytes in the linear part of the packet are read in place, the others are copied from its
+/// paged fragments.
+fn copy_bits(skb: &net::SkBuff, offset: usize, buf: &mut [u8]) -> Result {
+    let end = offset.checked_add(buf.len()).ok_or(EINVAL)?;
+    if let Some(bytes) = skb.head_data().get(offset..end) {
+        buf.copy_from_slice(bytes);
+        return Ok(());
+    }
+
+    // SAFETY: `buf` is valid for writes of `buf.len()` bytes, and `skb_copy_bits` fails if the
+    // range is not within the packet.
+    to_result(unsafe {
+        bindings::skb_copy_bits(
+            raw_skb(skb),
+            offset.try_into()?,
+            buf.as_mut_ptr().cast(),
+            buf.len().try_into()?,
+        )
+    })
+}
+
+/// Reads `N` bytes at `offset` from the packet data.
+fn read_bytes<const N: usize>(skb: &net::SkBuff, offset: usize) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    copy_bits(skb, offset, &mut buf)?;
+    Ok(buf)
+}
+
+/// Returns the `N` bytes of `bytes` starting at `offset`.
+fn array<const N: usize>(bytes: &[u8], offset: usize) -> [u8; N] {
+    let mut array = [0u8; N];
+    array.copy_from_slice(&bytes[offset..offset + N]);
+    array
+}
+
+/// An IPv4 or IPv6 address.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum IpAddr {
+    V4([u8; 4]),
+    V6([u8; 16]),
+}
+
+impl IpAddr {
+    fn octets(&self) -> &[u8] {
+        match self {
+            Self::V4(a) => a,
+            Self::V6(a) => a,
+        }
+    }
+}
+
+impl fmt::Display for IpAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::V4(a) => write!(f, "{}.{}.{}.{}", a[0], a[1], a[2], a[3]),
+            Self::V6(a) => {
+                // Zero groups are not compressed, e.g., `fe80:0:0:0:0:0:0:1`.
+                for (i, group) in a.chunks_exact(2).enumerate() {
+                    if i != 0 {
+                        f.write_str(":")?;
+                    }
+                    write!(f, "{:x}", u16::from_be_bytes([group[0], group[1]]))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// An address and port pair, formatted as `a.b.c.d:port` or `[v6]:port`.
+struct Endpoint(IpAddr, u16);
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            IpAddr::V4(_) => write!(f, "{}:{}", self.0, self.1),
+            IpAddr::V6(_) => write!(f, "[{}]:{}", self.0, self.1),
+        }
+    }
+}
+
+/// The fields of an IPv4 or IPv6 header that filters can match on.
+struct IpHeader {
+    src: IpAddr,
+    dst: IpAddr,
+
+    /// The transport protocol, found after any IPv6 extension headers.
+    protocol: u8,
+
+    /// The IPv4 time to live or IPv6 hop limit.
+    ttl: u8,
+}
+
+impl IpHeader {
+    /// Parses the network header of `skb`.
+    ///
+    /// Also returns the offset of the transport header, or `None` if the packet is a fragment
+    /// other than the first one and therefore has no transport header.
+    fn parse(skb: &net::SkBuff) -> Result<(Self, Option<usize>)> {
+        let [first] = read_bytes::<1>(skb, 0)?;
+        match first >> 4 {
+            4 => Self::parse_v4(skb),
+            6 => Self::parse_v6(skb),
+            _ => Err(EINVAL),
+        }
+    }
+
+    fn parse_v4(skb: &net::SkBuff) -> Result<(Self, Option<usize>)> {
+        let hdr = read_bytes::<20>(skb, 0)?;
+        let header_len = usize::from(hdr[0] & 0xf) * 4;
+        if header_len < hdr.len() {
+            return Err(EINVAL);
+        }
+
+        let fragment_offset = u16::from_be_bytes([hdr[6], hdr[7]]) & 0x1fff;
+        let ip = Self {
+            src: IpAddr::V4(array(&hdr, 12)),
+            dst: IpAddr::V4(array(&hdr, 16)),
+            protocol: hdr[9],
+            ttl: hdr[8],
+        };
+        Ok((ip, (fragment_offset == 0).then_some(header_len)))
+    }
+
+    fn parse_v6(skb: &net::SkBuff) -> Result<(Self, Option<usize>)> {
+        let hdr = read_bytes::<40>(skb, 0)?;
+        let mut ip = Self {
+            src: IpAddr::V6(array(&hdr, 8)),
+            dst: IpAddr::V6(array(&hdr, 24)),
+            protocol: hdr[6],
+            ttl: hdr[7],
+        };
+
+        let mut offset = hdr.len();
+        let mut first_fragment = true;
+        for _ in 0..MAX_IPV6_EXT_HEADERS {
+            match ip.protocol {
+                IPPROTO_HOPOPTS | IPPROTO_ROUTING | IPPROTO_DSTOPTS => {
+                    let [next, len] = read_bytes(skb, offset)?;
+                    ip.protocol = next;
+                    offset += (usize::from(len) + 1) * 8;
+                }
+                IPPROTO_AH => {
+                    let [next, len] = read_bytes(skb, offset)?;
+                    ip.protocol = next;
+                    offset += (usize::from(len) + 2) * 4;
+                }
+                IPPROTO_FRAGMENT => {
+                    let [next, _, off_hi, off_lo] = read_bytes(skb, offset)?;
+                    ip.protocol = next;
+                    offset += 8;
+                    first_fragment = u16::from_be_bytes([off_hi, off_lo]) & 0xfff8 == 0;
+                }
+                _ => return Ok((ip, first_fragment.then_some(offset))),
+            }
+        }
+
+        // Too many extension headers to find the transport header.
+        Err(EINVAL)
+    }
+}
+
+/// The flags of a TCP header.
+#[derive(Clone, Copy)]
+struct TcpFlags(u8);
+
+impl TcpFlags {
+    const NAMES: [&'static str; 8] = ["FIN", "SYN", "RST", "PSH", "ACK", "URG", "ECE", "CWR"];
+}
+
+impl fmt::Display for TcpFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sep = "";
+        for (bit, name) in Self::NAMES.iter().enumerate() {
+            if self.0 & (1 << bit) != 0 {
+                write!(f, "{sep}{name}")?;
+                sep = ",";
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The fields of a transport header that filters can match on.
+enum L4Header {
+    Tcp {
+        src_port: u16,
+        dst_port: u16,
+        flags: TcpFlags,
+    },
+    Udp {
+        src_port: u16,
+        dst_port: u16,
+    },
+    /// An ICMP or ICMPv6 header, depending on the IP protocol.
+    Icmp {
+        ty: u8,
+        code: u8,
+    },
+}
+
+impl L4Header {
+    /// Parses the transport header at `offset` in `skb`.
+    ///
+    /// Returns `None` for protocols it does not know about.
+    fn parse(skb: &net::SkBuff, protocol: u8, offset: usize) -> Result<Option<Self>> {
+        Ok(Some(match protocol {
+            IPPROTO_TCP => {
+                let hdr = read_bytes::<14>(skb, offset)?;
+                Self::Tcp {
+                    src_port: u16::from_be_bytes(array(&hdr, 0)),
+                    dst_port: u16::from_be_bytes(array(&hdr, 2)),
+                    flags: TcpFlags(hdr[13]),
+                }
+            }
+            IPPROTO_UDP => {
+                let hdr = read_bytes::<4>(skb, offset)?;
+                Self::Udp {
+                    src_port: u16::from_be_bytes(array(&hdr, 0)),
+                    dst_port: u16::from_be_bytes(array(&hdr, 2)),
+                }
+            }
+            IPPROTO_ICMP | IPPROTO_ICMPV6 => {
+                let [ty, code] = read_bytes(skb, offset)?;
+                Self::Icmp { ty, code }
+            }
+            _ => return Ok(None),
+        }))
+    }
+}
+
+/// The decoded headers of a packet.
+struct Packet {
+    ip: IpHeader,
+
+    /// The transport header, if the protocol is known and the packet is not a non-first fragment.
+    l4: Option<L4Header>,
+
+    mark: u32,
+    state: ConnState,
+}
+
+impl Packet {
+    /// Parses the network and transport headers of `skb`, which may be non-linear.
+    fn parse(skb: &net::SkBuff) -> Result<Self> {
+        let (ip, offset) = IpHeader::parse(skb)?;
+        let l4 = match offset {
+            Some(offset) => L4Header::parse(skb, ip.protocol, offset)?,
+            None => None,
+        };
+        Ok(Self {
+            ip,
+            l4,
+            mark: skb.mark(),
+            state: skb.conn_state(),
+        })
+    }
+}
+
+impl Packet {
+    /// Returns the source and destination ports of TCP and UDP packets.
+    fn ports(&self) -> Option<(u16, u16)> {
+        match self.l4 {
+            Some(L4Header::Tcp {
+                src_port, dst_port, ..
+            })
+            | Some(L4Header::Udp { src_port, dst_port }) => Some((src_port, dst_port)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ip = &self.ip;
+        match &self.l4 {
+            Some(L4Header::Tcp {
+                src_port,
+                dst_port,
+                flags,
+            }) => write!(
+                f,
+                "TCP {} -> {} [{flags}]",
+                Endpoint(ip.src, *src_port),
+                Endpoint(ip.dst, *dst_port)
+            )?,
+            Some(L4Header::Udp { src_port, dst_port }) => write!(
+                f,
+                "UDP {} -> {}",
+                Endpoint(ip.src, *src_port),
+                Endpoint(ip.dst, *dst_port)
+            )?,
+            Some(L4Header::Icmp { ty, code }) => {
+                let name = match ip.protocol {
+                    IPPROTO_ICMPV6 => "ICMPv6",
+                    _ => "ICMP",
+                };
+                write!(f, "{name} {} -> {} type={ty} code={code}", ip.src, ip.dst)?
+            }
+            None => write!(f, "proto {} {} -> {}", ip.protocol, ip.src, ip.dst)?,
+        }
+        write!(f, " ttl={}", ip.ttl)?;
+        if self.mark != 0 {
+            write!(f, " mark={:#x}", self.mark)?;
+        }
+        if self.state != ConnState::Invalid {
+            write!(f, " {}", self.state)?;
+        }
+        Ok(())
+    }
+}
+
+/// The direction of the packets seen by a hook.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    /// Packets received by this host.
+    In,
+    /// Packets routed through this host.
+    Forward,
+    /// Packets sent by this host.
+    Out,
+}
+
+/// What to do with the packets matching a rule.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Accept,
+    Drop,
+    /// Sends the packets to NFQUEUE `num`, accepting them instead if nobody listens and `bypass`
+    /// is set.
+    Queue {
+        num: u16,
+        bypass: bool,
+    },
+}
+
+impl Action {
+    /// Returns the netfilter verdict for the action.
+    fn verdict(self) -> c_uint {
+        match self {
+            Self::Accept => bindings::NF_ACCEPT,
+            Self::Drop => bindings::NF_DROP,
+            Self::Queue { num, bypass } => {
+                let verdict = (c_uint::from(num) << bindings::NF_VERDICT_QBITS)
+                    & bindings::NF_VERDICT_QMASK
+                    | bindings::NF_QUEUE;
+                if bypass {
+                    verdict | bindings::NF_VERDICT_FLAG_QUEUE_BYPASS
+                } else {
+                    verdict
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Accept => f.write_str("accept"),
+            Self::Drop => f.write_str("drop"),
+            Self::Queue { num, bypass } => {
+                write!(f, "queue {num}")?;
+                if *bypass {
+                    f.write_str(" bypass")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Parses an IPv6 address in the usual colon-separated form, with at most one `::`.
+fn parse_ipv6_addr(s: &str) -> Result<[u8; 16]> {
+    fn parse_groups(s: &str, groups: &mut [u16]) -> Result<usize> {
+        if s.is_empty() {
+            return Ok(0);
+        }
+        let mut count = 0;
+        for group in s.split(':') {
+            *groups.get_mut(count).ok_or(EINVAL)? =
+                u16::from_str_radix(group, 16).map_err(|_| EINVAL)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    let mut groups = [0u16; 8];
+    match s.split_once("::") {
+        None => {
+            if parse_groups(s, &mut groups)? != groups.len() {
+                return Err(EINVAL);
+            }
+        }
+        Some((head, tail)) => {
+            let mut tail_groups = [0u16; 7];
+            let tail_len = parse_groups(tail, &mut tail_groups)?;
+            parse_groups(head, &mut groups[..7 - tail_len])?;
+            groups[8 - tail_len..].copy_from_slice(&tail_groups[..tail_len]);
+        }
+    }
+
+    let mut addr = [0u8; 16];
+    for (bytes, group) in addr.chunks_exact_mut(2).zip(groups) {
+        bytes.copy_from_slice(&group.to_be_bytes());
+    }
+    Ok(addr)
+}
+
+/// Parses an IPv4 address in dotted decimal form.
+fn parse_ipv4_addr(s: &str) -> Result<[u8; 4]> {
+    let mut addr = [0u8; 4];
+    let mut parts = s.split('.');
+    for octet in &mut addr {
+        *octet = parts.next().ok_or(EINVAL)?.parse().map_err(|_| EINVAL)?;
+    }
+    if parts.next().is_some() {
+        return Err(EINVAL);
+    }
+    Ok(addr)
+}
+
+/// An address prefix, such as `10.0.0.0/8` or `fe80::/10`.
+#[derive(Clone, Copy)]
+struct Prefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Prefix {
+    fn parse(s: &str) -> Result<Self> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| EINVAL)?)),
+            None => (s, None),
+        };
+        let (mut addr, max_len) = if addr.contains(':') {
+            (IpAddr::V6(parse_ipv6_addr(addr)?), 128)
+        } else {
+            (IpAddr::V4(parse_ipv4_addr(addr)?), 32)
+        };
+        let len = len.unwrap_or(max_len);
+        if len > max_len {
+            return Err(EINVAL);
+        }
+
+        // Clear the host bits so that the prefix is listed in its canonical form.
+        let bytes: &mut [u8] = match &mut addr {
+            IpAddr::V4(a) => a,
+            IpAddr::V6(a) => a,
+        };
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let bits = usize::from(len).saturating_sub(i * 8).min(8);
+            *byte &= !(0xffu8.checked_shr(bits as u32).unwrap_or(0));
+        }
+        Ok(Self { addr, len })
+    }
+
+    fn contains(&self, addr: &IpAddr) -> bool {
+        let (prefix, addr): (&[u8], &[u8]) = match (&self.addr, addr) {
+            (IpAddr::V4(p), IpAddr::V4(a)) => (p, a),
+            (IpAddr::V6(p), IpAddr::V6(a)) => (p, a),
+            _ => return false,
+        };
+        let full = usize::from(self.len / 8);
+        let rest = self.len % 8;
+        prefix[..full] == addr[..full]
+            && (rest == 0 || (prefix[full] ^ addr[full]) & (0xff << (8 - rest)) == 0)
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// An inclusive range of ports.
+#[derive(Clone, Copy)]
+struct PortRange {
+    first: u16,
+    last: u16,
+}
+
+impl PortRange {
+    fn parse(s: &str) -> Result<Self> {
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let first = first.parse().map_err(|_| EINVAL)?;
+        let last = last.parse().map_err(|_| EINVAL)?;
+        if first > last {
+            return Err(EINVAL);
+        }
+        Ok(Self { first, last })
+    }
+
+    fn contains(&self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal number.
+fn parse_u32(s: &str) -> Result<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| EINVAL)
+}
+
+/// Matches the packet marks equal to `value` once masked with `mask`.
+#[derive(Clone, Copy)]
+struct MarkMatch {
+    value: u32,
+    mask: u32,
+}
+
+impl MarkMatch {
+    fn parse(s: &str) -> Result<Self> {
+        let (value, mask) = match s.split_once('/') {
+            Some((value, mask)) => (parse_u32(value)?, parse_u32(mask)?),
+            None => (parse_u32(s)?, u32::MAX),
+        };
+        if value & !mask != 0 {
+            return Err(EINVAL);
+        }
+        Ok(Self { value, mask })
+    }
+
+    fn matches(&self, mark: u32) -> bool {
+        mark & self.mask == self.value
+    }
+}
+
+impl fmt::Display for MarkMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.value)?;
+        if self.mask != u32::MAX {
+            write!(f, "/{:#x}", self.mask)?;
+        }
+        Ok(())
+    }
+}
+
+/// An entry of the rule table.
+struct Rule {
+    action: Action,
+    direction: Option<Direction>,
+    src: Option<Prefix>,
+    dst: Option<Prefix>,
+    protocol: Option<u8>,
+    src_ports: Option<PortRange>,
+    dst_ports: Option<PortRange>,
+
+    /// The set of connection states matched, as [`ConnState::bit`] values.
+    states: Option<u8>,
+    mark: Option<MarkMatch>,
+
+    /// The mark set on the packets that match the rule.
+    set_mark: Option<u32>,
+
+    /// Whether the packets exceeding the rate allowed to their source are dropped.
+    limit: bool,
+
+    /// The number of packets that matched the rule.
+    hits: AtomicU64,
+
+    /// The total length of the packets that matched the rule.
+    bytes: AtomicU64,
+}
+
+impl Rule {
+    /// Parses a rule in the format described in the module documentation.
+    fn parse(line: &str) -> Result<Self> {
+        let mut tokens = line.split_ascii_whitespace();
+        let action = match tokens.next() {
+            Some("accept") => Action::Accept,
+            Some("drop") => Action::Drop,
+            Some("queue") => Action::Queue {
+                num: tokens.next().ok_or(EINVAL)?.parse().map_err(|_| EINVAL)?,
+                bypass: false,
+            },
+            _ => return Err(EINVAL),
+        };
+        let mut rule = Self {
+            action,
+            direction: None,
+            src: None,
+            dst: None,
+            protocol: None,
+            src_ports: None,
+            dst_ports: None,
+            states: None,
+            mark: None,
+            set_mark: None,
+            limit: false,
+            hits: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        };
+
+        while let Some(token) = tokens.next() {
+            match token {
+                "in" => rule.direction = Some(Direction::In),
+                "forward" => rule.direction = Some(Direction::Forward),
+                "out" => rule.direction = Some(Direction::Out),
+                "bypass" => match &mut rule.action {
+                    Action::Queue { bypass, .. } => *bypass = true,
+                    _ => return Err(EINVAL),
+                },
+                "src" => rule.src = Some(Prefix::parse(tokens.next().ok_or(EINVAL)?)?),
+                "dst" => rule.dst = Some(Prefix::parse(tokens.next().ok_or(EINVAL)?)?),
+                "sport" => rule.src_ports = Some(PortRange::parse(tokens.next().ok_or(EINVAL)?)?),
+                "dport" => rule.dst_ports = Some(PortRange::parse(tokens.next().ok_or(EINVAL)?)?),
+                "state" => {
+                    let list = tokens.next().ok_or(EINVAL)?.as_bytes();
+                    let states = parse_names(list, "connection state", &ConnState::NAMES)?;
+                    let bits = states.iter().fold(0, |bits, state| bits | state.bit());
+                    if bits == 0 {
+                        return Err(EINVAL);
+                    }
+                    rule.states = Some(bits);
+                }
+                "mark" => rule.mark = Some(MarkMatch::parse(tokens.next().ok_or(EINVAL)?)?),
+                "setmark" => rule.set_mark = Some(parse_u32(tokens.next().ok_or(EINVAL)?)?),
+                "limit" => rule.limit = true,
+                "proto" => {
+                    rule.protocol = Some(match tokens.next().ok_or(EINVAL)? {
+                        "tcp" => IPPROTO_TCP,
+                        "udp" => IPPROTO_UDP,
+                        "icmp" => IPPROTO_ICMP,
+                        "icmpv6" => IPPROTO_ICMPV6,
+                        num => num.parse().map_err(|_| EINVAL)?,
+                    })
+                }
+                _ => return Err(EINVAL),
+            }
+        }
+        Ok(rule)
+    }
+
+    fn matches(&self, direction: Direction, packet: &Packet) -> bool {
+        if self.direction.map_or(false, |d| d != direction)
+            || self.src.map_or(false, |p| !p.contains(&packet.ip.src))
+            || self.dst.map_or(false, |p| !p.contains(&packet.ip.dst))
+            || self.protocol.map_or(false, |p| p != packet.ip.protocol)
+            || self.states.map_or(false, |s| s & packet.state.bit() == 0)
+            || self.mark.map_or(false, |m| !m.matches(packet.mark))
+        {
+            return false;
+        }
+
+        if self.src_ports.is_none() && self.dst_ports.is_none() {
+            return true;
+        }
+        match packet.ports() {
+            Some((src, dst)) => {
+                self.src_ports.map_or(true, |r| r.contains(src))
+                    && self.dst_ports.map_or(true, |r| r.contains(dst))
+            }
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.action)?;
+        match self.direction {
+            Some(Direction::In) => f.write_str(" in")?,
+            Some(Direction::Forward) => f.write_str(" forward")?,
+            Some(Direction::Out) => f.write_str(" out")?,
+            None => {}
+        }
+        if let Some(src) = &self.src {
+            write!(f, " src {src}")?;
+        }
+        if let Some(dst) = &self.dst {
+            write!(f, " dst {dst}")?;
+        }
+        match self.protocol {
+            Some(IPPROTO_TCP) => f.write_str(" proto tcp")?,
+            Some(IPPROTO_UDP) => f.write_str(" proto udp")?,
+            Some(IPPROTO_ICMP) => f.write_str(" proto icmp")?,
+            Some(IPPROTO_ICMPV6) => f.write_str(" proto icmpv6")?,
+            Some(num) => write!(f, " proto {num}")?,
+            None => {}
+        }
+        if let Some(ports) = &self.src_ports {
+            write!(f, " sport {ports}")?;
+        }
+        if let Some(ports) = &self.dst_ports {
+            write!(f, " dport {ports}")?;
+        }
+        if let Some(states) = self.states {
+            f.write_str(" state")?;
+            let mut sep = ' ';
+            for (name, state) in &ConnState::NAMES {
+                if states & state.bit() != 0 {
+                    write!(f, "{sep}{name}")?;
+                    sep = ',';
+                }
+            }
+        }
+        if let Some(mark) = &self.mark {
+            write!(f, " mark {mark}")?;
+        }
+        if let Some(mark) = self.set_mark {
+            write!(f, " setmark {mark:#x}")?;
+        }
+        if self.limit {
+            f.write_str(" limit")?;
+        }
+        Ok(())
+    }
+}
+
+/// The longest line accepted in the rule table, which bounds what is kept of a line whose end has
+/// not been written yet.
+const MAX_RULE_LINE: usize = 4096;
+
+/// Parses the rules in `text`, appending them to `rules`.
+fn parse_rules(text: &[u8], rules: &mut Vec<Rule>) -> Result {
+    for line in core::str::from_utf8(text)?.lines() {
+        let line = line.split_once('#').map_or(line, |(rule, _)| rule);
+        if !line.trim().is_empty() {
+            rules.try_push(Rule::parse(line)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// A set of rules, evaluated in order.
+struct RuleTable {
+    rules: Vec<Rule>,
+}
+
+impl RuleTable {
+    /// Returns the first rule matching `packet`, updating its counters.
+    fn evaluate(&self, direction: Direction, packet: &Packet, len: u32) -> Option<&Rule> {
+        let rule = self.rules.iter().find(|r| r.matches(direction, packet))?;
+        rule.hits.fetch_add(1, Ordering::Relaxed);
+        rule.bytes.fetch_add(len.into(), Ordering::Relaxed);
+        Some(rule)
+    }
+}
+
+/// The active rule table, read by the hooks without taking any locks.
+struct Rules {
+    /// The table owned by `owner`, read under RCU.
+    active: AtomicPtr<RuleTable>,
+
+    /// Keeps the active table alive and serialises its replacement.
+    owner: Mutex<Arc<RuleTable>>,
+}
+
+impl Rules {
+    fn try_new() -> Result<Arc<Self>> {
+        let table = Arc::try_new(RuleTable { rules: Vec::new() })?;
+        let mut rules = Pin::from(UniqueArc::try_new(Self {
+            active: AtomicPtr::new(&*table as *const RuleTable as *mut RuleTable),
+            // SAFETY: `mutex_init!` is called below.
+            owner: unsafe { Mutex::new(table) },
+        })?);
+
+        // SAFETY: `owner` is pinned when `rules` is.
+        let pinned = unsafe { rules.as_mut().map_unchecked_mut(|r| &mut r.owner) };
+        mutex_init!(pinned, "Rules::owner");
+
+        Ok(rules.into())
+    }
+
+    /// Returns the active table, which remains valid while `guard` is held.
+    fn read<'a>(&self, _guard: &'a rcu::Guard) -> &'a RuleTable {
+        // SAFETY: `active` always points to a table that is kept alive by `owner` at least until
+        // an RCU grace period has elapsed after it was replaced, and `guard` is an RCU read-side
+        // critical section that started before then.
+        unsafe { &*self.active.load(Ordering::Acquire) }
+    }
+
+    /// Returns a reference to the active table that may be held while sleeping.
+    fn get(&self) -> Arc<RuleTable> {
+        self.owner.lock().clone()
+    }
+
+    /// Makes `table` the active table, waiting until no hook uses the previous one.
+    fn replace(&self, table: Arc<RuleTable>) {
+        let old = {
+            let mut owner = self.owner.lock();
+            self.active.store(
+                &*table as *const RuleTable as *mut RuleTable,
+                Ordering::Release,
+            );
+            core::mem::replace(&mut *owner, table)
+        };
+
+        // SAFETY: FFI call with no additional requirements.
+        unsafe { bindings::synchronize_rcu() };
+        drop(old);
+    }
+}
+
+/// A `fmt::Write` implementation that appends to a vector, failing when it cannot grow.
+struct VecWriter(Vec<u8>);
+
+impl Write for VecWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0
+            .try_extend_from_slice(s.as_bytes())
+            .map_err(|_| fmt::Error)
+    }
+}
+
+/// The rules written through an open file of the misc device, which are not active yet.
+struct Staged {
+    rules: Vec<Rule>,
+
+    /// The beginning of a line whose end has not been written yet.
+    partial: Vec<u8>,
+
+    /// Set when a rule fails to parse, in which case the staged rules are discarded.
+    failed: bool,
+}
+
+/// An open file of the misc device.
+struct RulesFile {
+    rules: Arc<Rules>,
+    staged: Mutex<Staged>,
+}
+
+#[vtable]
+impl file::Operations for RulesFile {
+    type Data = Arc<Self>;
+    type OpenData = Arc<Rules>;
+
+    fn open(rules: &Arc<Rules>, _file: &File) -> Result<Arc<Self>> {
+        let mut state = Pin::from(UniqueArc::try_new(Self {
+            rules: rules.clone(),
+            // SAFETY: `mutex_init!` is called below.
+            staged: unsafe {
+                Mutex::new(Staged {
+                    rules: Vec::new(),
+                    partial: Vec::new(),
+                    failed: false,
+                })
+            },
+        })?);
+
+        // SAFETY: `staged` is pinned when `state` is.
+        let pinned = unsafe { state.as_mut().map_unchecked_mut(|s| &mut s.staged) };
+        mutex_init!(pinned, "RulesFile::staged");
+
+        Ok(state.into())
+    }
+
+    fn release(this: Arc<Self>, file: &File) {
+        if file.flags() & file::flags::O_ACCMODE == file::flags::O_RDONLY {
+            return;
+        }
+
+        let mut staged = this.staged.lock();
+        if !staged.failed {
+            // Parse the last line, in case it was not terminated by a newline.
+            let partial = core::mem::take(&mut staged.partial);
+            if parse_rules(&partial, &mut staged.rules).is_err() {
+                staged.failed = true;
+            }
+        }
+        if staged.failed {
+            pr_warn!("Invalid rules written, keeping the current rule table\n");
+            return;
+        }
+
+        let table = match Arc::try_new(RuleTable {
+            rules: core::mem::take(&mut staged.rules),
+        }) {
+            Ok(table) => table,
+            Err(_) => {
+                pr_warn!("Cannot allocate the new rule table\n");
+                return;
+            }
+        };
+        drop(staged);
+
+        let count = table.rules.len();
+        this.rules.replace(table);
+        pr_info!("Loaded {count} rules\n");
+    }
+
+    fn read(
+        this: ArcBorrow<'_, Self>,
+        _file: &File,
+        data: &mut impl IoBufferWriter,
+        offset: u64,
+    ) -> Result<usize> {
+        let table = this.rules.get();
+        let mut text = VecWriter(Vec::new());
+        for rule in &table.rules {
+            writeln!(
+                text,
+                "{rule} # hits={} bytes={}",
+                rule.hits.load(Ordering::Relaxed),
+                rule.bytes.load(Ordering::Relaxed)
+            )
+            .map_err(|_| ENOMEM)?;
+        }
+
+        let offset = usize::try_from(offset)?;
+        let remaining = match text.0.get(offset..) {
+            Some(remaining) => remaining,
+            None => return Ok(0),
+        };
+        let len = remaining.len().min(data.len());
+        data.write_slice(&remaining[..len])?;
+        Ok(len)
+    }
+
+    fn write(
+        this: ArcBorrow<'_, Self>,
+        _file: &File,
+        data: &mut impl IoBufferReader,
+        _offset: u64,
+    ) -> Result<usize> {
+        let len = data.len();
+        let mut staged = this.staged.lock();
+        if staged.failed {
+            return Err(EINVAL);
+        }
+
+        let Staged {
+            rules,
+            partial,
+            failed,
+        } = &mut *staged;
+        partial.try_extend_from_slice(&data.read_all()?)?;
+        if let Some(end) = partial.iter().rposition(|&b| b == b'\n') {
+            if let Err(e) = parse_rules(&partial[..end], rules) {
+                *failed = true;
+                return Err(e);
+            }
+            partial.drain(..=end);
+        }
+        if partial.len() > MAX_RULE_LINE {
+            *failed = true;
+            return Err(EINVAL);
+        }
+        Ok(len)
+    }
+}
+
+/// Marks the end of a list of flow entries.
+const NIL: u32 = u32::MAX;
+
+/// Identifies the flow a packet belongs to.
+///
+/// Flows are unidirectional: the two directions of a connection are separate flows.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct FlowKey {
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: u8,
+
+    /// The source and destination ports, or zero for protocols without ports.
+    ports: (u16, u16),
+}
+
+impl FlowKey {
+    fn new(packet: &Packet) -> Self {
+        Self {
+            src: packet.ip.src,
+            dst: packet.ip.dst,
+            protocol: packet.ip.protocol,
+            ports: packet.ports().unwrap_or((0, 0)),
+        }
+    }
+
+    fn hash(&self) -> u32 {
+        fnv1a(&[
+            self.src.octets(),
+            self.dst.octets(),
+            &[self.protocol],
+            &self.ports.0.to_be_bytes(),
+            &self.ports.1.to_be_bytes(),
+        ])
+    }
+}
+
+impl fmt::Display for FlowKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (src_port, dst_port) = self.ports;
+        match self.protocol {
+            IPPROTO_TCP => f.write_str("TCP ")?,
+            IPPROTO_UDP => f.write_str("UDP ")?,
+            IPPROTO_ICMP => f.write_str("ICMP ")?,
+            IPPROTO_ICMPV6 => f.write_str("ICMPv6 ")?,
+            num => write!(f, "proto {num} ")?,
+        }
+        match self.protocol {
+            IPPROTO_TCP | IPPROTO_UDP => write!(
+                f,
+                "{} -> {}",
+                Endpoint(self.src, src_port),
+                Endpoint(self.dst, dst_port)
+            ),
+            _ => write!(f, "{} -> {}", self.src, self.dst),
+        }
+    }
+}
+
+/// The counters of a flow, linked into a hash bucket and into the LRU list.
+#[derive(Clone)]
+struct FlowEntry {
+    key: FlowKey,
+    packets: u64,
+    bytes: u64,
+
+    /// The next entry in the same hash bucket.
+    chain: u32,
+
+    /// The entry seen just after this one, or `NIL` if this is the most recent one.
+    newer: u32,
+
+    /// The entry seen just before this one, or `NIL` if this is the least recent one.
+    older: u32,
+}
+
+/// A hash table of flows with a fixed number of entries.
+///
+/// All the memory is allocated upfront so that packets can be accounted in atomic context. Once
+/// all entries are in use, the least recently seen flow is evicted to make room for a new one.
+struct FlowTable {
+    entries: Vec<FlowEntry>,
+
+    /// The number of entries in use, which are the first ones of `entries`.
+    used: usize,
+
+    /// The first entry of each hash bucket. The number of buckets is a power of two.
+    buckets: Vec<u32>,
+
+    newest: u32,
+    oldest: u32,
+
+    /// The number of flows evicted to make room for new ones.
+    evictions: u64,
+}
+
+impl FlowTable {
+    fn try_new(max: usize) -> Result<Self> {
+        let unused = FlowEntry {
+            key: FlowKey {
+                src: IpAddr::V4([0; 4]),
+                dst: IpAddr::V4([0; 4]),
+                protocol: 0,
+                ports: (0, 0),
+            },
+            packets: 0,
+            bytes: 0,
+            chain: NIL,
+            newer: NIL,
+            older: NIL,
+        };
+        let mut entries = Vec::new();
+        entries.try_resize(max, unused)?;
+        let mut buckets = Vec::new();
+        buckets.try_resize(max.next_power_of_two(), NIL)?;
+        Ok(Self {
+            entries,
+            used: 0,
+            buckets,
+            newest: NIL,
+            oldest: NIL,
+            evictions: 0,
+        })
+    }
+
+    fn bucket(&self, key: &FlowKey) -> usize {
+        key.hash() as usize & (self.buckets.len() - 1)
+    }
+
+    /// Adds a packet of `len` bytes to the counters of flow `key`.
+    fn account(&mut self, key: &FlowKey, len: u32) {
+        let bucket = self.bucket(key);
+        let mut i = self.buckets[bucket];
+        while i != NIL {
+            let entry = &mut self.entries[i as usize];
+            if entry.key == *key {
+                entry.packets += 1;
+                entry.bytes += u64::from(len);
+                self.unlink(i);
+                self.push_newest(i);
+                return;
+            }
+            i = entry.chain;
+        }
+
+        let i = if self.used < self.entries.len() {
+            self.used += 1;
+            (self.used - 1) as u32
+        } else {
+            let oldest = self.oldest;
+            self.unlink(oldest);
+            self.unchain(oldest);
+            self.evictions += 1;
+            oldest
+        };
+
+        let entry = &mut self.entries[i as usize];
+        entry.key = *key;
+        entry.packets = 1;
+        entry.bytes = len.into();
+        entry.chain = self.buckets[bucket];
+        self.buckets[bucket] = i;
+        self.push_newest(i);
+    }
+
+    /// Removes entry `i` from the LRU list.
+    fn unlink(&mut self, i: u32) {
+        let FlowEntry { newer, older, .. } = self.entries[i as usize];
+        match newer {
+            NIL => self.newest = older,
+            _ => self.entries[newer as usize].older = older,
+        }
+        match older {
+            NIL => self.oldest = newer,
+            _ => self.entries[older as usize].newer = newer,
+        }
+    }
+
+    /// Inserts entry `i` at the most recent end of the LRU list.
+    fn push_newest(&mut self, i: u32) {
+        let newest = self.newest;
+        let entry = &mut self.entries[i as usize];
+        entry.newer = NIL;
+        entry.older = newest;
+        match newest {
+            NIL => self.oldest = i,
+            _ => self.entries[newest as usize].newer = i,
+        }
+        self.newest = i;
+    }
+
+    /// Removes entry `i` from its hash bucket.
+    fn unchain(&mut self, i: u32) {
+        let bucket = self.bucket(&self.entries[i as usize].key);
+        let next = self.entries[i as usize].chain;
+        if self.buckets[bucket] == i {
+            self.buckets[bucket] = next;
+            return;
+        }
+
+        let mut prev = self.buckets[bucket];
+        while self.entries[prev as usize].chain != i {
+            prev = self.entries[prev as usize].chain;
+        }
+        self.entries[prev as usize].chain = next;
+    }
+}
+
+/// The per-flow counters shared by all hooks.
+struct Flows {
+    /// Taken with interrupts disabled, as hooks may run in softirq context.
+    table: SpinLock<FlowTable>,
+
+    /// The number of entries of `table`.
+    capacity: usize,
+}
+
+impl Flows {
+    fn try_new(max: usize) -> Result<Arc<Self>> {
+        let mut flows = Pin::from(UniqueArc::try_new(Self {
+            // SAFETY: `spinlock_init!` is called below.
+            table: unsafe { SpinLock::new(FlowTable::try_new(max)?) },
+            capacity: max,
+        })?);
+
+        // SAFETY: `table` is pinned when `flows` is.
+        let pinned = unsafe { flows.as_mut().map_unchecked_mut(|f| &mut f.table) };
+        spinlock_init!(pinned, "Flows::table");
+
+        Ok(flows.into())
+    }
+
+    fn account(&self, packet: &Packet, len: u32) {
+        self.table
+            .lock_irqdisable()
+            .account(&FlowKey::new(packet), len);
+    }
+}
+
+/// A `fmt::Write` implementation that appends to the `seq_file` being shown.
+///
+/// It never fails: when the output does not fit, the `seq_file` notes it and calls the show
+/// function again with a larger buffer.
+struct SeqWriter(*mut bindings::seq_file);
+
+impl Write for SeqWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // SAFETY: The `seq_file` is valid while it is being shown, and `s` is valid for reads of
+        // its length.
+        unsafe { bindings::seq_write(self.0, s.as_ptr().cast(), s.len()) };
+        Ok(())
+    }
+}
+
+/// The `rust_netfilter` debugfs directory, which is removed when this is dropped.
+struct DebugFs {
+    dir: *mut bindings::dentry,
+    _fops: Box<bindings::file_operations>,
+    _flows: Arc<Flows>,
+}
+
+// SAFETY: `dir` is only used to remove the directory, which can be done from any thread.
+unsafe impl Send for DebugFs {}
+
+// SAFETY: `DebugFs` has no methods taking `&self`.
+unsafe impl Sync for DebugFs {}
+
+impl DebugFs {
+    fn try_new(flows: &Arc<Flows>, module: &'static ThisModule) -> Result<Self> {
+        let flows = flows.clone();
+
+        // SAFETY: All fields of `file_operations` are pointers or optional function pointers, for
+        // which zero is a valid value.
+        let mut fops = Box::try_new(unsafe { core::mem::zeroed::<bindings::file_operations>() })?;
+        fops.owner = module.as_ptr();
+        fops.open = Some(Self::open);
+        fops.read = Some(bindings::seq_read);
+        fops.llseek = Some(bindings::seq_lseek);
+        fops.release = Some(bindings::single_release);
+
+        // Errors are ignored, as debugfs functions handle a failed parent gracefully and the
+        // directory is not required for the module to work.
+        //
+        // SAFETY: `fops` and `flows` are kept alive by the `DebugFs`, whose `drop` removes the
+        // directory and waits for in-progress calls. `fops.owner` keeps the module loaded, and
+        // therefore the `DebugFs` alive, while the file is open.
+        let dir = unsafe {
+            let dir = bindings::debugfs_create_dir(
+                c_str!("rust_netfilter").as_char_ptr(),
+                core::ptr::null_mut(),
+            );
+            bindings::debugfs_create_file(
+                c_str!("top_talkers").as_char_ptr(),
+                0o444,
+                dir,
+                &*flows as *const Flows as *mut c_void,
+                &*fops,
+            );
+            dir
+        };
+
+        Ok(Self {
+            dir,
+            _fops: fops,
+            _flows: flows,
+        })
+    }
+
+    unsafe extern "C" fn open(inode: *mut bindings::inode, file: *mut bindings::file) -> c_int {
+        // SAFETY: The caller passes valid pointers. `i_private` is the `Flows` passed to
+        // `debugfs_create_file`.
+        unsafe { bindings::single_open(file, Some(Self::show_top_talkers), (*inode).i_private) }
+    }
+
+    unsafe extern "C" fn show_top_talkers(m: *mut bindings::seq_file, _: *mut c_void) -> c_int {
+        // SAFETY: `private` is the `Flows` passed to `single_open`, which is kept alive by the
+        // `DebugFs`.
+        let flows = unsafe { &*((*m).private as *const Flows) };
+
+        // Copy the counters so that they are sorted without the lock held.
+        let mut top = match Vec::try_with_capacity(flows.capacity) {
+            Ok(top) => top,
+            Err(_) => return ENOMEM.to_errno(),
+        };
+        let (used, evictions) = {
+            let table = flows.table.lock_irqdisable();
+            for entry in &table.entries[..table.used] {
+                // This never allocates, as the capacity is that of the table.
+                let _ = top.try_push((entry.bytes, entry.packets, entry.key));
+            }
+            (table.used, table.evictions)
+        };
+        top.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+        let mut out = SeqWriter(m);
+        let _ = Self::write_top_talkers(&mut out, used, evictions, &top);
+        0
+    }
+
+    fn write_top_talkers(
+        out: &mut SeqWriter,
+        used: usize,
+        evictions: u64,
+        top: &[(u64, u64, FlowKey)],
+    ) -> fmt::Result {
+        writeln!(out, "flows: {used}\nevictions: {evictions}")?;
+        writeln!(out, "\n{:>14} {:>10} flow", "bytes", "packets")?;
+        for (bytes, packets, key) in top.iter().take(*top_flows.read() as usize) {
+            writeln!(out, "{bytes:>14} {packets:>10} {key}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DebugFs {
+    fn drop(&mut self) {
+        // SAFETY: `dir` was returned by `debugfs_create_dir` and has not been removed yet.
+        unsafe { bindings::debugfs_remove(self.dir) };
+    }
+}
+
+/// How often the sources tracked by [`RateLimiter`] are garbage collected.
+const LIMIT_GC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The token bucket of a source address.
+#[derive(Clone, Copy)]
+struct TokenBucket {
+    src: IpAddr,
+
+    /// The credit of the source, in nanoseconds. Each packet costs [`RateLimiter::cost_ns`].
+    credit_ns: u64,
+
+    /// When `credit_ns` was last updated.
+    updated_ns: i64,
+
+    /// The next entry of the same hash chain, or of the free list.
+    chain: u32,
+
+    /// The source seen just after this one, or `NIL` if this is the most recent one.
+    newer: u32,
+
+    /// The source seen just before this one, or `NIL` if this is the least recent one.
+    older: u32,
+}
+
+impl TokenBucket {
+    /// Returns the credit of the source at time `now`, which is at most `capacity_ns`.
+    fn credit_at(&self, now: i64, capacity_ns: u64) -> u64 {
+        let elapsed = now.saturating_sub(self.updated_ns).max(0) as u64;
+        self.credit_ns.saturating_add(elapsed).min(capacity_ns)
+    }
+}
+
+/// A hash table of token buckets with a fixed number of entries.
+///
+/// Like [`FlowTable`], all the memory is allocated upfront, and the least recently seen source is
+/// evicted to make room for a new one once all entries are in use. Entries are otherwise only
+/// removed by [`SourceTable::collect`], as a full bucket behaves like a missing one.
+struct SourceTable {
+    entries: Vec<TokenBucket>,
+
+    /// The first entry of each hash chain. The number of chains is a power of two.
+    chains: Vec<u32>,
+
+    /// The first unused entry, whose `chain` links to the next one.
+    free: u32,
+    used: usize,
+
+    newest: u32,
+    oldest: u32,
+
+    /// The number of sources evicted to make room for new ones.
+    evictions: u64,
+
+    /// The waker of [`collect_sources`], while it waits for a source to be tracked.
+    gc_waker: Option<Waker>,
+}
+
+impl SourceTable {
+    fn try_new(max: usize) -> Result<Self> {
+        let mut entries = Vec::try_with_capacity(max)?;
+        for i in 1..=max {
+            entries.try_push(TokenBucket {
+                src: IpAddr::V4([0; 4]),
+                credit_ns: 0,
+                updated_ns: 0,
+                chain: if i < max { i as u32 } else { NIL },
+                newer: NIL,
+                older: NIL,
+            })?;
+        }
+        let mut chains = Vec::new();
+        chains.try_resize(max.next_power_of_two(), NIL)?;
+        Ok(Self {
+            entries,
+            chains,
+            free: if max > 0 { 0 } else { NIL },
+            used: 0,
+            newest: NIL,
+            oldest: NIL,
+            evictions: 0,
+            gc_waker: None,
+        })
+    }
+
+    fn chain(&self, src: &IpAddr) -> usize {
+        fnv1a(&[src.octets()]) as usize & (self.chains.len() - 1)
+    }
+
+    /// Returns the bucket of `src`, adding a full one if it has none.
+    fn get_or_insert(&mut self, src: &IpAddr, capacity_ns: u64, now: i64) -> &mut TokenBucket {
+        let chain = self.chain(src);
+        let mut i = self.chains[chain];
+        while i != NIL && self.entries[i as usize].src != *src {
+            i = self.entries[i as usize].chain;
+        }
+
+        if i != NIL {
+            self.unlink(i);
+        } else {
+            i = self.free;
+            if i == NIL {
+                i = self.oldest;
+                self.unlink(i);
+                self.unchain(i);
+                self.evictions += 1;
+            } else {
+                self.free = self.entries[i as usize].chain;
+                self.used += 1;
+            }
+            self.entries[i as usize] = TokenBucket {
+                src: *src,
+                credit_ns: capacity_ns,
+                updated_ns: now,
+                chain: self.chains[chain],
+                newer: NIL,
+                older: NIL,
+            };
+            self.chains[chain] = i;
+        }
+        self.push_newest(i);
+        &mut self.entries[i as usize]
+    }
+
+    /// Removes the buckets that are full at time `now`.
+    fn collect(&mut self, capacity_ns: u64, now: i64) {
+        for chain in 0..self.chains.len() {
+            let mut prev = NIL;
+            let mut i = self.chains[chain];
+            while i != NIL {
+                let entry = self.entries[i as usize];
+                if entry.credit_at(now, capacity_ns) < capacity_ns {
+                    prev = i;
+                } else {
+                    match prev {
+                        NIL => self.chains[chain] = entry.chain,
+                        _ => self.entries[prev as usize].chain = entry.chain,
+                    }
+                    self.unlink(i);
+                    self.entries[i as usize].chain = self.free;
+                    self.free = i;
+                    self.used -= 1;
+                }
+                i = entry.chain;
+            }
+        }
+    }
+
+    /// Removes entry `i` from the LRU list.
+    fn unlink(&mut self, i: u32) {
+        let TokenBucket { newer, older, .. } = self.entries[i as usize];
+        match newer {
+            NIL => self.newest = older,
+            _ => self.entries[newer as usize].older = older,
+        }
+        match older {
+            NIL => self.oldest = newer,
+            _ => self.entries[older as usize].newer = newer,
+        }
+    }
+
+    /// Inserts entry `i` at the most recent end of the LRU list.
+    fn push_newest(&mut self, i: u32) {
+        let newest = self.newest;
+        let entry = &mut self.entries[i as usize];
+        entry.newer = NIL;
+        entry.older = newest;
+        match newest {
+            NIL => self.oldest = i,
+            _ => self.entries[newest as usize].newer = i,
+        }
+        self.newest = i;
+    }
+
+    /// Removes entry `i` from its hash chain.
+    fn unchain(&mut self, i: u32) {
+        let chain = self.chain(&self.entries[i as usize].src);
+        let next = self.entries[i as usize].chain;
+        if self.chains[chain] == i {
+            self.chains[chain] = next;
+            return;
+        }
+
+        let mut prev = self.chains[chain];
+        while self.entries[prev as usize].chain != i {
+            prev = self.entries[prev as usize].chain;
+        }
+        self.entries[prev as usize].chain = next;
+    }
+}
+
+/// Limits the rate of the packets of each source address with token buckets.
+struct RateLimiter {
+    /// The credit needed to accept a packet, in nanoseconds.
+    cost_ns: u64,
+
+    /// The maximum credit of a source, which is that of a full burst.
+    capacity_ns: u64,
+
+    table: SpinLock<SourceTable>,
+
+    /// The number of packets dropped for exceeding the rate of their source.
+    limited: AtomicU64,
+}
+
+impl RateLimiter {
+    fn try_new(rate: u32, burst: u32, max: usize) -> Result<Arc<Self>> {
+        let cost_ns = 1_000_000_000 / u64::from(rate.max(1));
+        let mut limiter = Pin::from(UniqueArc::try_new(Self {
+            cost_ns,
+            capacity_ns: cost_ns.saturating_mul(burst.max(1).into()),
+            // SAFETY: `spinlock_init!` is called below.
+            table: unsafe { SpinLock::new(SourceTable::try_new(max)?) },
+            limited: AtomicU64::new(0),
+        })?);
+
+        // SAFETY: `table` is pinned when `limiter` is.
+        let pinned = unsafe { limiter.as_mut().map_unchecked_mut(|l| &mut l.table) };
+        spinlock_init!(pinned, "RateLimiter::table");
+
+        Ok(limiter.into())
+    }
+
+    /// Returns whether a packet from `src` is within the rate, taking its cost if so.
+    fn allow(&self, src: &IpAddr) -> bool {
+        let now = now_ns();
+        let mut table = self.table.lock_irqdisable();
+        let waker = table.gc_waker.take();
+        let bucket = table.get_or_insert(src, self.capacity_ns, now);
+
+        bucket.credit_ns = bucket.credit_at(now, self.capacity_ns);
+        bucket.updated_ns = now;
+        let allowed = bucket.credit_ns >= self.cost_ns;
+        if allowed {
+            bucket.credit_ns -= self.cost_ns;
+        } else {
+            self.limited.fetch_add(1, Ordering::Relaxed);
+        }
+        drop(table);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        allowed
+    }
+
+    /// Waits until the table tracks at least one source.
+    fn tracking(&self) -> impl Future<Output = ()> + '_ {
+        future::poll_fn(move |cx| {
+            let mut table = self.table.lock_irqdisable();
+            if table.used > 0 {
+                Poll::Ready(())
+            } else {
+                table.gc_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+/// Removes the sources whose bucket is full every [`LIMIT_GC_INTERVAL`], while any is tracked.
+async fn collect_sources(limiter: Arc<RateLimiter>) {
+    loop {
+        limiter.tracking().await;
+        sleep(LIMIT_GC_INTERVAL).await;
+        let now = now_ns();
+        limiter
+            .table
+            .lock_irqdisable()
+            .collect(limiter.capacity_ns, now);
+    }
+}
+
+/// The link type of captured packets, which start at the IP header.
+const LINKTYPE_RAW: u32 = 101;
+
+/// The size of the header of each packet record in a pcap file.
+const PCAP_RECORD_HEADER_LEN: usize = 16;
+
+/// The minimum size of the capture buffer.
+const MIN_CAPTURE_BUFFER_SIZE: usize = 4096;
+
+/// The maximum number of bytes returned by a single read of the capture device.
+const CAPTURE_READ_MAX: usize = 64 * 1024;
+
+/// The pcap file header, in native byte order.
+fn pcap_file_header(snaplen: u32) -> [u8; 24] {
+    let mut header = [0u8; 24];
+    header[0..4].copy_from_slice(&0xa1b2c3d4u32.to_ne_bytes());
+    header[4..6].copy_from_slice(&2u16.to_ne_bytes());
+    header[6..8].copy_from_slice(&4u16.to_ne_bytes());
+    // The time zone offset and timestamp accuracy are left as zero.
+    header[16..20].copy_from_slice(&snaplen.to_ne_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_RAW.to_ne_bytes());
+    header
+}
+
+/// A byte ring buffer, written by the hooks and read through the capture device.
+struct Ring {
+    buf: Vec<u8>,
+
+    /// The total number of bytes written, the next of which goes to `head % buf.len()`.
+    head: usize,
+
+    /// The total number of bytes read, the next of which comes from `tail % buf.len()`.
+    tail: usize,
+}
+
+impl Ring {
+    fn try_new(size: usize) -> Result<Self> {
+        let mut buf = Vec::new();
+        buf.try_resize(size, 0)?;
+        Ok(Self {
+            buf,
+            head: 0,
+            tail: 0,
+        })
+    }
+
+    fn used(&self) -> usize {
+        self.head - self.tail
+    }
+
+    /// Returns the regions of the buffer holding the `len` bytes written at position `pos`.
+    fn regions(&mut self, pos: usize, len: usize) -> (&mut [u8], &mut [u8]) {
+        let start = pos % self.buf.len();
+        let first = len.min(self.buf.len() - start);
+        let (wrapped, rest) = self.buf.split_at_mut(start);
+        (&mut rest[..first], &mut wrapped[..len - first])
+    }
+
+    /// Writes `bytes` at position `pos`, which the caller must know to be free.
+    fn write_at(&mut self, pos: usize, bytes: &[u8]) {
+        let (first, second) = self.regions(pos, bytes.len());
+        let (a, b) = bytes.split_at(first.len());
+        first.copy_from_slice(a);
+        second.copy_from_slice(b);
+    }
+
+    /// Appends a pcap record with `header` and the first `caplen` bytes of `skb`.
+    ///
+    /// Returns `false` if the record does not fit in the free space.
+    fn push_record(
+        &mut self,
+        header: &[u8; PCAP_RECORD_HEADER_LEN],
+        skb: &net::SkBuff,
+        caplen: usize,
+    ) -> bool {
+        let len = header.len() + caplen;
+        if self.buf.len() - self.used() < len {
+            return false;
+        }
+
+        let pos = self.head;
+        self.write_at(pos, header);
+        let (first, second) = self.regions(pos + header.len(), caplen);
+        let first_len = first.len();
+        if copy_bits(skb, 0, first).is_err() || copy_bits(skb, first_len, second).is_err() {
+            return false;
+        }
+        self.head += len;
+        true
+    }
+
+    /// Moves up to `out.len()` bytes out of the buffer into `out`, returning how many were moved.
+    fn pop(&mut self, out: &mut [u8]) -> usize {
+        let len = out.len().min(self.used());
+        let (first, second) = self.regions(self.tail, len);
+        out[..first.len()].copy_from_slice(first);
+        out[first.len()..len].copy_from_slice(second);
+        self.tail += len;
+        len
+    }
+}
+
+/// The packet capture shared by the hooks and the capture device.
+struct Capture {
+    snaplen: u32,
+
+    /// Taken with interrupts disabled, as hooks may run in softirq context.
+    ring: SpinLock<Ring>,
+
+    /// Notified when records are added to `ring`.
+    readable: CondVar,
+
+    /// Whether the capture device is open.
+    open: AtomicBool,
+
+    /// Whether packets are captured, which they are while the device is open.
+    capturing: AtomicBool,
+
+    /// The number of packets captured since the device was opened.
+    captured: AtomicU64,
+
+    /// The number of packets not captured because the buffer was full.
+    dropped: AtomicU64,
+}
+
+impl Capture {
+    fn try_new(snaplen: u32, size: usize) -> Result<Arc<Self>> {
+        let mut capture = Pin::from(UniqueArc::try_new(Self {
+            snaplen,
+            // SAFETY: `spinlock_init!` is called below.
+            ring: unsafe { SpinLock::new(Ring::try_new(size)?) },
+            // SAFETY: `condvar_init!` is called below.
+            readable: unsafe { CondVar::new() },
+            open: AtomicBool::new(false),
+            capturing: AtomicBool::new(false),
+            captured: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        })?);
+
+        // SAFETY: `ring` is pinned when `capture` is.
+        let pinned = unsafe { capture.as_mut().map_unchecked_mut(|c| &mut c.ring) };
+        spinlock_init!(pinned, "Capture::ring");
+
+        // SAFETY: `readable` is pinned when `capture` is.
+        let pinned = unsafe { capture.as_mut().map_unchecked_mut(|c| &mut c.readable) };
+        condvar_init!(pinned, "Capture::readable");
+
+        Ok(capture.into())
+    }
+
+    /// Adds `skb` to the capture if the device is open.
+    fn capture(&self, skb: &net::SkBuff) {
+        if !self.capturing.load(Ordering::Acquire) {
+            return;
+        }
+
+        let len = skb.len();
+        let caplen = len.min(self.snaplen);
+        let mut ts = bindings::timespec64::default();
+        // SAFETY: `ts` is valid for writes.
+        unsafe { bindings::ktime_get_real_ts64(&mut ts) };
+
+        let mut header = [0u8; PCAP_RECORD_HEADER_LEN];
+        header[0..4].copy_from_slice(&(ts.tv_sec as u32).to_ne_bytes());
+        header[4..8].copy_from_slice(&((ts.tv_nsec / 1000) as u32).to_ne_bytes());
+        header[8..12].copy_from_slice(&caplen.to_ne_bytes());
+        header[12..16].copy_from_slice(&len.to_ne_bytes());
+
+        let pushed = self
+            .ring
+            .lock_irqdisable()
+            .push_record(&header, skb, caplen as usize);
+        if pushed {
+            self.captured.fetch_add(1, Ordering::Relaxed);
+            self.readable.notify_one();
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The open capture device, of which there is at most one at a time.
+struct CaptureFile {
+    capture: Arc<Capture>,
 }
 
-impl netfilter::Filter for RustNetfilter {
-    fn filter(_: (), skb: &net::SkBuff) -> Disposition {
-        let data = skb.head_data();
+#[vtable]
+impl file::Operations for CaptureFile {
+    type Data = Box<Self>;
+    type OpenData = Arc<Capture>;
+
+    fn open(capture: &Arc<Capture>, _file: &File) -> Result<Box<Self>> {
+        let file = Box::try_new(Self {
+            capture: capture.clone(),
+        })?;
+        if capture
+            .open
+            .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(EBUSY);
+        }
+
+        {
+            let mut ring = capture.ring.lock_irqdisable();
+            ring.head = 0;
+            ring.tail = 0;
+            let header = pcap_file_header(capture.snaplen);
+            ring.write_at(0, &header);
+            ring.head = header.len();
+        }
+        capture.captured.store(0, Ordering::Relaxed);
+        capture.dropped.store(0, Ordering::Relaxed);
+
+        // Only start capturing once the file header is in place.
+        capture.capturing.store(true, Ordering::Release);
+        Ok(file)
+    }
+
+    fn release(this: Box<Self>, _file: &File) {
+        let capture = &this.capture;
+        capture.capturing.store(false, Ordering::Relaxed);
+        capture.open.store(false, Ordering::Relaxed);
         pr_info!(
-            "packet headlen={}, len={}, first bytes={:02x?}\n",
-            data.len(),
-            skb.len(),
-            &data[..core::cmp::min(10, data.len())]
+            "pcap: {} packets captured, {} dropped\n",
+            capture.captured.load(Ordering::Relaxed),
+            capture.dropped.load(Ordering::Relaxed)
+        );
+    }
+
+    fn read(
+        this: &Self,
+        file: &File,
+        data: &mut impl IoBufferWriter,
+        _offset: u64,
+    ) -> Result<usize> {
+        let capture = &this.capture;
+        let mut buf = Vec::new();
+        buf.try_resize(data.len().min(CAPTURE_READ_MAX), 0)?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = {
+            let mut ring = capture.ring.lock_irqdisable();
+            while ring.used() == 0 {
+                if file.flags() & file::flags::O_NONBLOCK != 0 {
+                    return Err(EAGAIN);
+                }
+                if capture.readable.wait(&mut ring) {
+                    return Err(EINTR);
+                }
+            }
+            ring.pop(&mut buf)
+        };
+
+        data.write_slice(&buf[..len])?;
+        Ok(len)
+    }
+}
+
+/// How packets are logged, as configured by the module parameters.
+struct PacketLog {
+    /// Only one in every `sample` packets is considered for logging.
+    sample: u64,
+
+    /// Limits the number of packets logged per interval. `___ratelimit` only ever trylocks it, so
+    /// it can be used in the hooks.
+    ratelimit: Opaque<bindings::ratelimit_state>,
+}
+
+// SAFETY: `ratelimit` is only used through `___ratelimit`, which serialises callers with the lock
+// embedded in it.
+unsafe impl Send for PacketLog {}
+
+// SAFETY: See `Send` above.
+unsafe impl Sync for PacketLog {}
+
+impl PacketLog {
+    /// Returns the logging configuration, or `None` if logging is disabled.
+    ///
+    /// The ratelimit must be initialised with [`PacketLog::init_ratelimit`] before use.
+    fn from_params() -> Result<Option<Self>> {
+        if !*log_packets.read() {
+            return Ok(None);
+        }
+
+        let sample = *log_sample.read();
+        if sample == 0 {
+            pr_err!("log_sample must be at least 1\n");
+            return Err(EINVAL);
+        }
+
+        Ok(Some(Self {
+            sample: sample.into(),
+            ratelimit: Opaque::uninit(),
+        }))
+    }
+
+    /// Initialises the ratelimit from the `log_interval_ms` and `log_burst` parameters.
+    ///
+    /// # Safety
+    ///
+    /// `self` must not be moved afterwards, and must not be in use yet.
+    unsafe fn init_ratelimit(&self) {
+        // SAFETY: FFI call with no additional requirements.
+        let interval = unsafe { bindings::__msecs_to_jiffies(*log_interval_ms.read()) };
+        // SAFETY: By the safety requirements, the state stays at this address and nothing uses it
+        // concurrently.
+        unsafe {
+            bindings::ratelimit_state_init(
+                self.ratelimit.get(),
+                interval.try_into().unwrap_or(c_int::MAX),
+                (*log_burst.read()).try_into().unwrap_or(c_int::MAX),
+            )
+        };
+    }
+
+    /// Returns whether a packet may be logged now.
+    ///
+    /// Once an interval is over, the number of packets that were not logged is reported.
+    fn allow(&self) -> bool {
+        // SAFETY: The state was initialised by `init_ratelimit`.
+        unsafe {
+            bindings::___ratelimit(self.ratelimit.get(), c_str!("rust_netfilter").as_char_ptr())
+                != 0
+        }
+    }
+}
+
+/// A protocol family whose packets can be hooked.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum HookFamily {
+    Ipv4,
+    Ipv6,
+    Arp,
+    Bridge,
+}
+
+impl HookFamily {
+    const NAMES: [(&'static str, Self); 4] = [
+        ("ipv4", Self::Ipv4),
+        ("ipv6", Self::Ipv6),
+        ("arp", Self::Arp),
+        ("bridge", Self::Bridge),
+    ];
+
+    fn nfproto(self) -> u8 {
+        let nfproto = match self {
+            Self::Ipv4 => bindings::NFPROTO_IPV4,
+            Self::Ipv6 => bindings::NFPROTO_IPV6,
+            Self::Arp => bindings::NFPROTO_ARP,
+            Self::Bridge => bindings::NFPROTO_BRIDGE,
+        };
+        // `NFPROTO_NUMPROTO` is 13, so every family fits in the `u8` of `nf_hook_ops::pf`.
+        nfproto as u8
+    }
+}
+
+/// A point of the packet path at which hooks are called.
+///
+/// The values are the numbers of the IP hooks (`enum nf_inet_hooks`), which the bridge ones share.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum HookPoint {
+    PreRouting = 0,
+    LocalIn = 1,
+    Forward = 2,
+    LocalOut = 3,
+    PostRouting = 4,
+}
+
+impl HookPoint {
+    const NAMES: [(&'static str, Self); 5] = [
+        ("prerouting", Self::PreRouting),
+        ("input", Self::LocalIn),
+        ("forward", Self::Forward),
+        ("output", Self::LocalOut),
+        ("postrouting", Self::PostRouting),
+    ];
+
+    fn direction(self) -> Direction {
+        match self {
+            Self::PreRouting | Self::LocalIn => Direction::In,
+            Self::Forward => Direction::Forward,
+            Self::LocalOut | Self::PostRouting => Direction::Out,
+        }
+    }
+}
+
+/// Returns the name of `value` in `names`.
+fn name_of<T: PartialEq>(names: &[(&'static str, T)], value: &T) -> &'static str {
+    names
+        .iter()
+        .find(|(_, v)| v == value)
+        .map_or("?", |(name, _)| name)
+}
+
+/// Parses one of the names in `names`.
+fn parse_name<T: Copy>(token: &str, what: &str, names: &[(&'static str, T)]) -> Result<T> {
+    match names.iter().find(|(name, _)| *name == token) {
+        Some((_, value)) => Ok(*value),
+        None => {
+            pr_err!("Unknown {what}: {token}\n");
+            Err(EINVAL)
+        }
+    }
+}
+
+/// Parses a comma-separated list of the names in `names`.
+fn parse_names<T: Copy>(list: &[u8], what: &str, names: &[(&'static str, T)]) -> Result<Vec<T>> {
+    let mut values = Vec::new();
+    for token in core::str::from_utf8(list)?.split(',').map(str::trim) {
+        if !token.is_empty() {
+            values.try_push(parse_name(token, what, names)?)?;
+        }
+    }
+    Ok(values)
+}
+
+/// The built-in hook relative to which the priority of the hooks is given.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PriorityBase {
+    First,
+    Raw,
+    Conntrack,
+    Mangle,
+    NatDst,
+    Filter,
+    Security,
+    NatSrc,
+    Last,
+}
+
+impl PriorityBase {
+    const NAMES: [(&'static str, Self); 9] = [
+        ("first", Self::First),
+        ("raw", Self::Raw),
+        ("conntrack", Self::Conntrack),
+        ("mangle", Self::Mangle),
+        ("nat_dst", Self::NatDst),
+        ("filter", Self::Filter),
+        ("security", Self::Security),
+        ("nat_src", Self::NatSrc),
+        ("last", Self::Last),
+    ];
+
+    /// Returns the priority of the base in `family`, or `None` if the family has no such hook.
+    ///
+    /// The IPv6 and ARP priorities are those of IPv4, bridges have their own.
+    fn value(self, family: HookFamily) -> Option<i32> {
+        if family == HookFamily::Bridge {
+            return match self {
+                Self::First => Some(bindings::nf_br_hook_priorities_NF_BR_PRI_FIRST),
+                Self::NatDst => Some(bindings::nf_br_hook_priorities_NF_BR_PRI_NAT_DST_BRIDGED),
+                Self::Filter => Some(bindings::nf_br_hook_priorities_NF_BR_PRI_FILTER_BRIDGED),
+                Self::NatSrc => Some(bindings::nf_br_hook_priorities_NF_BR_PRI_NAT_SRC),
+                Self::Last => Some(bindings::nf_br_hook_priorities_NF_BR_PRI_LAST),
+                _ => None,
+            };
+        }
+        Some(match self {
+            Self::First => bindings::nf_ip_hook_priorities_NF_IP_PRI_FIRST,
+            Self::Raw => bindings::nf_ip_hook_priorities_NF_IP_PRI_RAW,
+            Self::Conntrack => bindings::nf_ip_hook_priorities_NF_IP_PRI_CONNTRACK,
+            Self::Mangle => bindings::nf_ip_hook_priorities_NF_IP_PRI_MANGLE,
+            Self::NatDst => bindings::nf_ip_hook_priorities_NF_IP_PRI_NAT_DST,
+            Self::Filter => bindings::nf_ip_hook_priorities_NF_IP_PRI_FILTER,
+            Self::Security => bindings::nf_ip_hook_priorities_NF_IP_PRI_SECURITY,
+            Self::NatSrc => bindings::nf_ip_hook_priorities_NF_IP_PRI_NAT_SRC,
+            Self::Last => bindings::nf_ip_hook_priorities_NF_IP_PRI_LAST,
+        })
+    }
+}
+
+/// Identifies a hook by its family and point.
+#[derive(Clone, Copy)]
+struct HookId {
+    family: HookFamily,
+    point: HookPoint,
+}
+
+impl HookId {
+    /// Returns the netfilter hook number, or `None` if the family has no such hook.
+    fn hooknum(self) -> Option<c_uint> {
+        Some(match (self.family, self.point) {
+            (HookFamily::Arp, HookPoint::LocalIn) => bindings::NF_ARP_IN,
+            (HookFamily::Arp, HookPoint::Forward) => bindings::NF_ARP_FORWARD,
+            (HookFamily::Arp, HookPoint::LocalOut) => bindings::NF_ARP_OUT,
+            (HookFamily::Arp, HookPoint::PreRouting | HookPoint::PostRouting) => return None,
+            (_, point) => point as c_uint,
+        })
+    }
+
+    /// Returns whether `skb`, seen by the hook, is an IP packet.
+    ///
+    /// ARP hooks never see one, and bridge hooks see every frame that crosses the bridge, so
+    /// only the ones whose link layer protocol is IPv4 or IPv6 are.
+    fn carries_ip(self, skb: &net::SkBuff) -> bool {
+        match self.family {
+            HookFamily::Ipv4 | HookFamily::Ipv6 => true,
+            HookFamily::Arp => false,
+            HookFamily::Bridge => {
+                let protocol = u32::from(skb.protocol());
+                protocol == bindings::ETH_P_IP || protocol == bindings::ETH_P_IPV6
+            }
+        }
+    }
+}
+
+impl fmt::Display for HookId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            name_of(&HookFamily::NAMES, &self.family),
+            name_of(&HookPoint::NAMES, &self.point)
+        )
+    }
+}
+
+/// The state of one of the registered hooks.
+struct Hook {
+    id: HookId,
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    log: Option<PacketLog>,
+    rules: Arc<Rules>,
+    flows: Option<Arc<Flows>>,
+    capture: Option<Arc<Capture>>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Hook {
+    fn try_new(
+        id: HookId,
+        rules: &Arc<Rules>,
+        flows: &Option<Arc<Flows>>,
+        capture: &Option<Arc<Capture>>,
+        limiter: &Arc<RateLimiter>,
+    ) -> Result<Arc<Self>> {
+        let hook = Arc::try_new(Self {
+            id,
+            packets: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            log: PacketLog::from_params()?,
+            rules: rules.clone(),
+            flows: flows.clone(),
+            capture: capture.clone(),
+            limiter: limiter.clone(),
+        })?;
+        if let Some(log) = &hook.log {
+            // SAFETY: `log` stays in the `Arc` and is not used until the hook is registered.
+            unsafe { log.init_ratelimit() };
+        }
+        Ok(hook)
+    }
+
+    unsafe extern "C" fn hook(
+        priv_: *mut c_void,
+        skb: *mut bindings::sk_buff,
+        _state: *const bindings::nf_hook_state,
+    ) -> c_uint {
+        // SAFETY: `priv_` is the `Hook` set in the `nf_hook_ops`, which `PernetHooks` keeps alive
+        // while they are registered. `skb` is valid for the duration of the call, and `SkBuff` is
+        // a transparent wrapper of `sk_buff`.
+        let (hook, skb) = unsafe { (&*(priv_ as *const Self), &*(skb as *const net::SkBuff)) };
+        hook.filter(skb)
+    }
+
+    fn filter(&self, skb: &net::SkBuff) -> c_uint {
+        let seq = self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(skb.len().into(), Ordering::Relaxed);
+
+        let log = self
+            .log
+            .as_ref()
+            .map_or(false, |log| seq % log.sample == 0 && log.allow());
+
+        if !self.id.carries_ip(skb) {
+            if log {
+                pr_info!("{}: #{seq} len={}\n", self.id, skb.len());
+            }
+            return bindings::NF_ACCEPT;
+        }
+
+        if let Some(capture) = &self.capture {
+            capture.capture(skb);
+        }
+
+        let guard = rcu::read_lock();
+        let table = self.rules.read(&guard);
+        if table.rules.is_empty() && self.flows.is_none() && !log {
+            return bindings::NF_ACCEPT;
+        }
+
+        let packet = match Packet::parse(skb) {
+            Ok(packet) => packet,
+            Err(e) => {
+                if log {
+                    pr_info!(
+                        "{}: #{seq} len={}, cannot parse headers: {:?}\n",
+                        self.id,
+                        skb.len(),
+                        e
+                    );
+                }
+                return bindings::NF_ACCEPT;
+            }
+        };
+
+        if let Some(flows) = &self.flows {
+            flows.account(&packet, skb.len());
+        }
+
+        let rule = table.evaluate(self.id.point.direction(), &packet, skb.len());
+        if let Some(mark) = rule.and_then(|rule| rule.set_mark) {
+            skb.set_mark(mark);
+        }
+        let mut action = rule.map_or(Action::Accept, |rule| rule.action);
+        if rule.map_or(false, |rule| rule.limit) && !self.limiter.allow(&packet.ip.src) {
+            action = Action::Drop;
+        }
+        if log {
+            pr_info!("{}: #{seq} {packet} len={} {action}\n", self.id, skb.len());
+        }
+        action.verdict()
+    }
+}
+
+/// The registered [`PernetHooks`].
+///
+/// The `pernet_operations` callbacks are only given the namespace, so this is how they find the
+/// hooks to register in it.
+static PERNET_HOOKS: AtomicPtr<PernetHooks> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Hooks registered in every network namespace, including the ones created later, until dropped.
+struct PernetHooks {
+    ops: bindings::pernet_operations,
+    hook_ops: Vec<bindings::nf_hook_ops>,
+    hooks: Vec<Arc<Hook>>,
+
+    /// The families for which connection tracking is enabled along with the hooks.
+    conntrack: Vec<u8>,
+
+    /// Whether the operations are registered. Until they are, the hooks are being registered in
+    /// the existing namespaces, and a failure to do so fails the registration.
+    registered: AtomicBool,
+
+    /// The addresses of the namespaces created later in which the hooks could not be registered.
+    skipped: Mutex<Vec<usize>>,
+}
+
+// SAFETY: The raw pointers in the operations are only used by the networking core, which does so
+// from any thread.
+unsafe impl Send for PernetHooks {}
+
+// SAFETY: `PernetHooks` has no methods taking `&self` that use the raw pointers.
+unsafe impl Sync for PernetHooks {}
+
+impl PernetHooks {
+    fn try_new(
+        selected: Vec<(Arc<Hook>, u8, c_uint, i32)>,
+        conntrack: Vec<u8>,
+    ) -> Result<Pin<Box<Self>>> {
+        let mut hook_ops = Vec::try_with_capacity(selected.len())?;
+        let mut owned = Vec::try_with_capacity(selected.len())?;
+        for (hook, pf, hooknum, priority_value) in selected {
+            // SAFETY: All fields of `nf_hook_ops` are integers, pointers or optional function
+            // pointers, for which zero is a valid value. A zero `hook_ops_type` is that of
+            // ordinary hooks.
+            let mut ops = unsafe { core::mem::zeroed::<bindings::nf_hook_ops>() };
+            ops.hook = Some(Hook::hook);
+            ops.priv_ = &*hook as *const Hook as *mut c_void;
+            ops.pf = pf;
+            ops.hooknum = hooknum;
+            ops.priority = priority_value;
+            // Neither push allocates, as the capacity is that of `selected`.
+            hook_ops.try_push(ops)?;
+            owned.try_push(hook)?;
+        }
+
+        // SAFETY: All fields of `pernet_operations` are pointers, integers or optional function
+        // pointers, for which zero is a valid value.
+        let mut ops = unsafe { core::mem::zeroed::<bindings::pernet_operations>() };
+        ops.init = Some(Self::init_net);
+        ops.exit = Some(Self::exit_net);
+
+        let mut this = Pin::from(Box::try_new(Self {
+            ops,
+            hook_ops,
+            hooks: owned,
+            conntrack,
+            registered: AtomicBool::new(false),
+            // SAFETY: `mutex_init!` is called below.
+            skipped: unsafe { Mutex::new(Vec::new()) },
+        })?);
+
+        // SAFETY: `skipped` is pinned when `this` is.
+        let pinned = unsafe { this.as_mut().map_unchecked_mut(|h| &mut h.skipped) };
+        mutex_init!(pinned, "PernetHooks::skipped");
+
+        // SAFETY: Nothing is moved out of `this`, whose address is published below.
+        let ptr = unsafe { this.as_mut().get_unchecked_mut() as *mut Self };
+        if PERNET_HOOKS
+            .compare_exchange(
+                core::ptr::null_mut(),
+                ptr,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return Err(EBUSY);
+        }
+
+        // SAFETY: `this` is published above and is only freed after `drop` unregisters the
+        // operations. Registering calls `init_net` for every existing namespace.
+        let ret = unsafe { bindings::register_pernet_subsys(&mut (*ptr).ops) };
+        to_result(ret)?;
+        this.registered.store(true, Ordering::Release);
+        Ok(this)
+    }
+
+    /// Returns the registered [`PernetHooks`].
+    ///
+    /// # Safety
+    ///
+    /// Must only be called from the `pernet_operations` callbacks.
+    unsafe fn get<'a>() -> &'a Self {
+        // SAFETY: The pointer is set before the operations are registered, and cleared and freed
+        // after they are unregistered, which waits for the callbacks.
+        unsafe { &*PERNET_HOOKS.load(Ordering::Acquire) }
+    }
+
+    unsafe extern "C" fn init_net(net: *mut bindings::net) -> c_int {
+        // SAFETY: Called by the networking core for the operations registered by `try_new`.
+        let this = unsafe { Self::get() };
+        // SAFETY: `net` is valid, and the hooks are unregistered by `exit_net` before `this` is
+        // freed.
+        let ret = unsafe { this.register(net) };
+        if ret == 0 || !this.registered.load(Ordering::Acquire) {
+            return ret;
+        }
+
+        // The hooks were registered in the existing namespaces when the module was loaded, so a
+        // failure here is one of this namespace, which is still created, only without the hooks.
+        let mut skipped = this.skipped.lock();
+        if let Err(e) = skipped.try_push(net as usize) {
+            return Error::from(e).to_errno();
+        }
+        pr_warn!("Cannot register the hooks in a new namespace: error {ret}\n");
+        0
+    }
+
+    unsafe extern "C" fn exit_net(net: *mut bindings::net) {
+        // SAFETY: Called by the networking core for the operations registered by `try_new`.
+        let this = unsafe { Self::get() };
+        let mut skipped = this.skipped.lock();
+        if let Some(i) = skipped.iter().position(|&skipped| skipped == net as usize) {
+            skipped.swap_remove(i);
+            return;
+        }
+        drop(skipped);
+
+        // SAFETY: The hooks were registered in `net` by `init_net`, as it would have been skipped
+        // otherwise.
+        unsafe {
+            bindings::nf_unregister_net_hooks(net, this.hook_ops.as_ptr(), this.hook_ops.len() as _)
+        };
+        // SAFETY: Connection tracking was enabled in `net` by `init_net`.
+        unsafe { this.put_conntrack(net, this.conntrack.len()) };
+    }
+
+    /// Enables connection tracking and registers the hooks in `net`.
+    ///
+    /// # Safety
+    ///
+    /// `net` must be valid, and the hooks unregistered from it before `self` is freed.
+    unsafe fn register(&self, net: *mut bindings::net) -> c_int {
+        // SAFETY: `net` is valid by the safety requirements.
+        let ret = unsafe { self.get_conntrack(net) };
+        if ret < 0 {
+            return ret;
+        }
+        // SAFETY: `net` is valid, and the hooks are unregistered in time, by the safety
+        // requirements.
+        let ret = unsafe {
+            bindings::nf_register_net_hooks(net, self.hook_ops.as_ptr(), self.hook_ops.len() as _)
+        };
+        if ret < 0 {
+            // SAFETY: Connection tracking was enabled in `net` above.
+            unsafe { self.put_conntrack(net, self.conntrack.len()) };
+        }
+        ret
+    }
+
+    /// Enables connection tracking in `net` for the families in `conntrack`.
+    ///
+    /// # Safety
+    ///
+    /// `net` must be valid.
+    #[cfg(CONFIG_NF_CONNTRACK)]
+    unsafe fn get_conntrack(&self, net: *mut bindings::net) -> c_int {
+        for (i, &pf) in self.conntrack.iter().enumerate() {
+            // SAFETY: `net` is valid by the safety requirements.
+            let ret = unsafe { bindings::nf_ct_netns_get(net, pf) };
+            if ret < 0 {
+                // SAFETY: Connection tracking was enabled for the previous families above.
+                unsafe { self.put_conntrack(net, i) };
+                return ret;
+            }
+        }
+        0
+    }
+
+    /// Releases connection tracking in `net` for the first `count` families in `conntrack`.
+    ///
+    /// # Safety
+    ///
+    /// `net` must be valid, and connection tracking enabled in it for these families.
+    #[cfg(CONFIG_NF_CONNTRACK)]
+    unsafe fn put_conntrack(&self, net: *mut bindings::net, count: usize) {
+        for &pf in &self.conntrack[..count] {
+            // SAFETY: By the safety requirements.
+            unsafe { bindings::nf_ct_netns_put(net, pf) };
+        }
+    }
+
+    #[cfg(not(CONFIG_NF_CONNTRACK))]
+    unsafe fn get_conntrack(&self, _net: *mut bindings::net) -> c_int {
+        0
+    }
+
+    #[cfg(not(CONFIG_NF_CONNTRACK))]
+    unsafe fn put_conntrack(&self, _net: *mut bindings::net, _count: usize) {}
+}
+
+impl Drop for PernetHooks {
+    fn drop(&mut self) {
+        if *self.registered.get_mut() {
+            // SAFETY: The operations were registered by `try_new`. Unregistering them calls
+            // `exit_net` for every namespace.
+            unsafe { bindings::unregister_pernet_subsys(&mut self.ops) };
+        }
+        // Only clear the pointer if it is to `self`, as `try_new` fails with `EBUSY` when another
+        // instance is registered.
+        let _ = PERNET_HOOKS.compare_exchange(
+            self as *mut Self,
+            core::ptr::null_mut(),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
         );
-        Disposition::Accept
     }
 }
 
+struct RustNetfilter {
+    hooks: Pin<Box<PernetHooks>>,
+    limiter: Arc<RateLimiter>,
+    /// Runs [`collect_sources`]. Stopping it on unload drops the task.
+    _gc: AutoStopHandle<dyn Executor>,
+    _dev: Pin<Box<miscdev::Registration<RulesFile>>>,
+    _debugfs: Option<DebugFs>,
+    _pcap: Option<Pin<Box<miscdev::Registration<CaptureFile>>>>,
+}
+
 impl kernel::Module for RustNetfilter {
-    fn init(_name: &'static CStr, _module: &'static ThisModule) -> Result<Self> {
+    fn init(name: &'static CStr, module: &'static ThisModule) -> Result<Self> {
+        let rules = Rules::try_new()?;
+        let flows = match *max_flows.read() {
+            0 => None,
+            max => Some(Flows::try_new(max as usize)?),
+        };
+        let debugfs = match &flows {
+            Some(flows) => Some(DebugFs::try_new(flows, module)?),
+            None => None,
+        };
+        let capture = match *capture_packets.read() {
+            true => {
+                let size = *capture_buffer_size.read() as usize;
+                if size < MIN_CAPTURE_BUFFER_SIZE {
+                    pr_err!("capture_buffer_size must be at least {MIN_CAPTURE_BUFFER_SIZE}\n");
+                    return Err(EINVAL);
+                }
+                // A record holds a header and up to `snaplen` bytes, and must fit in the buffer.
+                let snaplen = *capture_snaplen.read();
+                let max = size - PCAP_RECORD_HEADER_LEN;
+                if snaplen == 0 || snaplen as usize > max {
+                    pr_err!("capture_snaplen must be between 1 and {max}\n");
+                    return Err(EINVAL);
+                }
+                Some(Capture::try_new(snaplen, size)?)
+            }
+            false => None,
+        };
+        let pcap = match &capture {
+            Some(capture) => Some(miscdev::Registration::new_pinned(
+                fmt!("{name}_pcap"),
+                capture.clone(),
+            )?),
+            None => None,
+        };
+
+        let base = parse_name(
+            core::str::from_utf8(priority_base.read())?.trim(),
+            "priority base",
+            &PriorityBase::NAMES,
+        )?;
+        if *limit_rate.read() == 0 || *limit_max_sources.read() == 0 {
+            pr_err!("limit_rate and limit_max_sources must not be zero\n");
+            return Err(EINVAL);
+        }
+        let limiter = RateLimiter::try_new(
+            *limit_rate.read(),
+            *limit_burst.read(),
+            *limit_max_sources.read() as usize,
+        )?;
+        let gc = WqExecutor::try_new(kernel::workqueue::system())?;
+        spawn_task!(gc.executor(), collect_sources(limiter.clone()))?;
+
+        let mut selected = Vec::new();
+        let mut conntrack = Vec::new();
+        let points = parse_names(hooks.read(), "hook", &HookPoint::NAMES)?;
+        for family in parse_names(families.read(), "family", &HookFamily::NAMES)? {
+            let base_value = match base.value(family) {
+                Some(value) => value,
+                None => {
+                    pr_err!(
+                        "There is no {} priority for the {} family\n",
+                        name_of(&PriorityBase::NAMES, &base),
+                        name_of(&HookFamily::NAMES, &family)
+                    );
+                    return Err(EINVAL);
+                }
+            };
+            if *track_connections.read()
+                && matches!(family, HookFamily::Ipv4 | HookFamily::Ipv6)
+                && !conntrack.contains(&family.nfproto())
+            {
+                conntrack.try_push(family.nfproto())?;
+            }
+            for &point in &points {
+                let id = HookId { family, point };
+                let hooknum = match id.hooknum() {
+                    Some(hooknum) => hooknum,
+                    None => {
+                        pr_warn!("There is no {id} hook, skipping it\n");
+                        continue;
+                    }
+                };
+                let hook = Hook::try_new(id, &rules, &flows, &capture, &limiter)?;
+                selected.try_push((
+                    hook,
+                    family.nfproto(),
+                    hooknum,
+                    base_value.saturating_add(*priority.read()),
+                ))?;
+            }
+        }
+        if selected.is_empty() {
+            pr_err!("No hooks selected\n");
+            return Err(EINVAL);
+        }
+
         Ok(Self {
-            _in: netfilter::Registration::new_pinned(
-                Family::INet(inet::Hook::PreRouting),
-                0,
-                net::init_ns().into(),
-                None,
-                (),
-            )?,
-            _out: netfilter::Registration::new_pinned(
-                Family::INet(inet::Hook::PostRouting),
-                0,
-                net::init_ns().into(),
-                None,
-                (),
-            )?,
+            hooks: PernetHooks::try_new(selected, conntrack)?,
+            limiter,
+            _gc: gc.into(),
+            _dev: miscdev::Registration::new_pinned(fmt!("{name}"), rules)?,
+            _debugfs: debugfs,
+            _pcap: pcap,
         })
     }
 }
+
+impl Drop for RustNetfilter {
+    fn drop(&mut self) {
+        for hook in &self.hooks.hooks {
+            pr_info!(
+                "{}: {} packets, {} bytes\n",
+                hook.id,
+                hook.packets.load(Ordering::Relaxed),
+                hook.bytes.load(Ordering::Relaxed)
+            );
+        }
+        pr_info!(
+            "limit: {} packets over the rate of their source, {} sources evicted\n",
+            self.limiter.limited.load(Ordering::Relaxed),
+            self.limiter.table.lock_irqdisable().evictions
+        );
+    }
+}
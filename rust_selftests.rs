@@ -80,12 +80,305 @@ fn test_example() -> Result<TestSummary> {
     Ok(Pass)
 }
 
+/// A dentry of a [`RustFsMount`], put when dropped.
+struct RustFsDentry(*mut kernel::bindings::dentry);
+
+impl RustFsDentry {
+    /// Returns the inode, or null if the entry does not exist.
+    fn inode(&self) -> *mut kernel::bindings::inode {
+        // SAFETY: The dentry is referenced until it is dropped.
+        unsafe { (*self.0).d_inode }
+    }
+
+    /// Returns the link count of the inode, which must exist.
+    fn nlink(&self) -> u32 {
+        // SAFETY: A positive dentry references its inode.
+        unsafe { (*self.inode()).__bindgen_anon_1.i_nlink }
+    }
+}
+
+impl Drop for RustFsDentry {
+    fn drop(&mut self) {
+        // SAFETY: `RustFsMount` returns referenced dentries.
+        unsafe { kernel::bindings::dput(self.0) };
+    }
+}
+
+/// A private mount of `rustfs`, the file system of the `rust_fs` sample, unmounted when dropped.
+///
+/// The tests only use entries of its root directory, and run the namespace operations on them
+/// with [`RustFsMount::locked`].
+struct RustFsMount(*mut kernel::bindings::vfsmount);
+
+impl RustFsMount {
+    /// Mounts a new instance, failing with `ENODEV` if `rust_fs` is not loaded.
+    fn new() -> Result<Self> {
+        use core::ffi::c_void;
+        use kernel::{bindings, c_str};
+
+        // SAFETY: The name is a valid NUL-terminated string.
+        let fs_type = unsafe { bindings::get_fs_type(c_str!("rustfs").as_char_ptr()) };
+        if fs_type.is_null() {
+            return Err(ENODEV);
+        }
+        // SAFETY: `fs_type` is referenced until `put_filesystem`, after which the mount, if any,
+        // holds its own reference.
+        let mnt = unsafe {
+            let mnt = bindings::kern_mount(fs_type);
+            bindings::put_filesystem(fs_type);
+            mnt
+        };
+        // SAFETY: `kern_mount` returns a mount or an error pointer.
+        if unsafe { bindings::IS_ERR(mnt as *const c_void) } {
+            // SAFETY: `mnt` is an error pointer.
+            let errno = unsafe { bindings::PTR_ERR(mnt as *const c_void) };
+            return Err(Error::from_errno(errno as _));
+        }
+        Ok(Self(mnt))
+    }
+
+    /// Returns the root directory.
+    fn root(&self) -> RustFsDentry {
+        // SAFETY: The mount references its root, so another reference can be taken.
+        RustFsDentry(unsafe { kernel::bindings::dget((*self.0).mnt_root) })
+    }
+
+    /// Looks up `name` in the root directory, which is negative if it does not exist.
+    fn lookup(&self, name: &CStr) -> Result<RustFsDentry> {
+        use core::ffi::{c_int, c_void};
+        use kernel::bindings;
+
+        let root = self.root();
+        // SAFETY: `name` is valid for reads of its length.
+        let dentry = unsafe {
+            bindings::lookup_one_len_unlocked(name.as_char_ptr(), root.0, name.len() as c_int)
+        };
+        // SAFETY: `lookup_one_len_unlocked` returns a dentry or an error pointer.
+        if unsafe { bindings::IS_ERR(dentry as *const c_void) } {
+            // SAFETY: `dentry` is an error pointer.
+            let errno = unsafe { bindings::PTR_ERR(dentry as *const c_void) };
+            return Err(Error::from_errno(errno as _));
+        }
+        Ok(RustFsDentry(dentry))
+    }
+
+    /// Runs `op` with the user namespace of the mount and the inode of the root directory, which
+    /// is locked meanwhile.
+    fn locked(
+        &self,
+        op: impl FnOnce(*mut kernel::bindings::user_namespace, *mut kernel::bindings::inode) -> i32,
+    ) -> Result {
+        use kernel::bindings;
+
+        let root = self.root();
+        // SAFETY: Locking a directory with itself only locks it once, and it is unlocked below.
+        // Only the address of the initial user namespace is taken.
+        let ret = unsafe {
+            bindings::lock_rename(root.0, root.0);
+            let ret = op(
+                core::ptr::addr_of_mut!(bindings::init_user_ns),
+                root.inode(),
+            );
+            bindings::unlock_rename(root.0, root.0);
+            ret
+        };
+        kernel::error::to_result(ret)
+    }
+}
+
+impl Drop for RustFsMount {
+    fn drop(&mut self) {
+        // SAFETY: The mount is not used after this.
+        unsafe { kernel::bindings::kern_unmount(self.0) };
+    }
+}
+
+/// Tests creating symbolic links in `rustfs`.
+fn test_rustfs_symlink() -> Result<TestSummary> {
+    use kernel::{bindings, c_str};
+
+    let mnt = RustFsMount::new()?;
+    let symlink = |target: &CStr| {
+        let link = mnt.lookup(c_str!("link"))?;
+        // SAFETY: The directory is locked, `link` is in it, and `target` is NUL-terminated.
+        mnt.locked(|userns, dir| unsafe {
+            bindings::vfs_symlink(userns, dir, link.0, target.as_char_ptr())
+        })
+    };
+
+    symlink(c_str!("some/target"))?;
+    let inode = mnt.lookup(c_str!("link"))?.inode();
+    // SAFETY: The link is pinned in the cache until it is removed.
+    if inode.is_null()
+        || u32::from(unsafe { (*inode).i_mode }) & bindings::S_IFMT != bindings::S_IFLNK
+    {
+        return Ok(Fail);
+    }
+
+    // Links can be dangling, but not replace existing entries.
+    if symlink(c_str!("other")) != Err(EEXIST) {
+        return Ok(Fail);
+    }
+    Ok(Pass)
+}
+
+/// Tests the link counts of files with hard links in `rustfs`.
+fn test_rustfs_link() -> Result<TestSummary> {
+    use core::ptr;
+    use kernel::{bindings, c_str};
+
+    let mnt = RustFsMount::new()?;
+    let a = mnt.lookup(c_str!("a"))?;
+    let b = mnt.lookup(c_str!("b"))?;
+    let mode = (bindings::S_IFREG | 0o644) as bindings::umode_t;
+    // SAFETY: The directory is locked, and the dentries are in it.
+    mnt.locked(|userns, dir| unsafe { bindings::vfs_create(userns, dir, a.0, mode, true) })?;
+    // SAFETY: As above.
+    mnt.locked(|userns, dir| unsafe {
+        bindings::vfs_link(a.0, userns, dir, b.0, ptr::null_mut())
+    })?;
+    if a.inode() != b.inode() || a.nlink() != 2 {
+        return Ok(Fail);
+    }
+
+    // SAFETY: As above.
+    mnt.locked(|userns, dir| unsafe { bindings::vfs_unlink(userns, dir, a.0, ptr::null_mut()) })?;
+    if b.nlink() != 1 || !mnt.lookup(c_str!("a"))?.inode().is_null() {
+        return Ok(Fail);
+    }
+    Ok(Pass)
+}
+
+/// Tests renaming entries of `rustfs`, with and without `RENAME_NOREPLACE` and `RENAME_EXCHANGE`.
+fn test_rustfs_rename() -> Result<TestSummary> {
+    use core::ptr;
+    use kernel::{bindings, c_str};
+
+    let mnt = RustFsMount::new()?;
+    let create = |name: &CStr| {
+        let dentry = mnt.lookup(name)?;
+        let mode = (bindings::S_IFREG | 0o644) as bindings::umode_t;
+        // SAFETY: The directory is locked, and `dentry` is in it.
+        mnt.locked(|userns, dir| unsafe {
+            bindings::vfs_create(userns, dir, dentry.0, mode, true)
+        })?;
+        Ok::<_, Error>(dentry.inode())
+    };
+    let rename = |old: &CStr, new: &CStr, flags: u32| {
+        let (old, new) = (mnt.lookup(old)?, mnt.lookup(new)?);
+        mnt.locked(|userns, dir| {
+            let mut data = bindings::renamedata {
+                old_mnt_userns: userns,
+                old_dir: dir,
+                old_dentry: old.0,
+                new_mnt_userns: userns,
+                new_dir: dir,
+                new_dentry: new.0,
+                delegated_inode: ptr::null_mut(),
+                flags,
+            };
+            // SAFETY: The directory, which is both the old and new one, is locked, and the
+            // dentries are in it.
+            unsafe { bindings::vfs_rename(&mut data) }
+        })
+    };
+    let inode = |name: &CStr| Ok::<_, Error>(mnt.lookup(name)?.inode());
+
+    let a = create(c_str!("a"))?;
+    let b = create(c_str!("b"))?;
+
+    let noreplace = bindings::RENAME_NOREPLACE;
+    if rename(c_str!("a"), c_str!("b"), noreplace) != Err(EEXIST) {
+        return Ok(Fail);
+    }
+
+    rename(c_str!("a"), c_str!("b"), bindings::RENAME_EXCHANGE)?;
+    if inode(c_str!("a"))? != b || inode(c_str!("b"))? != a {
+        return Ok(Fail);
+    }
+
+    // `RENAME_EXCHANGE` needs both entries to exist.
+    if rename(c_str!("a"), c_str!("c"), bindings::RENAME_EXCHANGE) != Err(ENOENT) {
+        return Ok(Fail);
+    }
+
+    rename(c_str!("a"), c_str!("c"), noreplace)?;
+    if !inode(c_str!("a"))?.is_null() || inode(c_str!("c"))? != b {
+        return Ok(Fail);
+    }
+
+    // Renaming over `b` drops the inode it refers to, which `a` had before the exchange.
+    rename(c_str!("c"), c_str!("b"), 0)?;
+    if !inode(c_str!("c"))?.is_null() || inode(c_str!("b"))? != b {
+        return Ok(Fail);
+    }
+    Ok(Pass)
+}
+
+/// Tests the link counts of directories of `rustfs` and of their parent as they are created,
+/// renamed over each other and removed.
+fn test_rustfs_dir_nlink() -> Result<TestSummary> {
+    use core::ptr;
+    use kernel::{bindings, c_str};
+
+    let mnt = RustFsMount::new()?;
+    let root = mnt.root();
+    let mkdir = |name: &CStr| {
+        let dentry = mnt.lookup(name)?;
+        let mode = (bindings::S_IFDIR | 0o755) as bindings::umode_t;
+        // SAFETY: The directory is locked, and `dentry` is in it.
+        mnt.locked(|userns, dir| unsafe { bindings::vfs_mkdir(userns, dir, dentry.0, mode) })?;
+        Ok::<_, Error>(dentry)
+    };
+
+    // The root has a link from its own `.` and `..`, and one more from the `..` of each
+    // subdirectory, which also has one from its `.`.
+    let d = mkdir(c_str!("d"))?;
+    let e = mkdir(c_str!("e"))?;
+    if root.nlink() != 4 || d.nlink() != 2 || e.nlink() != 2 {
+        return Ok(Fail);
+    }
+
+    // Renaming over an empty directory removes it, along with the link of its `..`.
+    let (old, new) = (mnt.lookup(c_str!("d"))?, mnt.lookup(c_str!("e"))?);
+    mnt.locked(|userns, dir| {
+        let mut data = bindings::renamedata {
+            old_mnt_userns: userns,
+            old_dir: dir,
+            old_dentry: old.0,
+            new_mnt_userns: userns,
+            new_dir: dir,
+            new_dentry: new.0,
+            delegated_inode: ptr::null_mut(),
+            flags: 0,
+        };
+        // SAFETY: The directory, which is both the old and new one, is locked, and the dentries
+        // are in it.
+        unsafe { bindings::vfs_rename(&mut data) }
+    })?;
+    drop((old, new));
+    let renamed = mnt.lookup(c_str!("e"))?;
+    if root.nlink() != 3 || e.nlink() != 0 || renamed.inode() != d.inode() || d.nlink() != 2 {
+        return Ok(Fail);
+    }
+
+    // SAFETY: The directory is locked, and `renamed` is in it.
+    mnt.locked(|userns, dir| unsafe { bindings::vfs_rmdir(userns, dir, renamed.0) })?;
+    if root.nlink() != 2 || d.nlink() != 0 || !mnt.lookup(c_str!("e"))?.inode().is_null() {
+        return Ok(Fail);
+    }
+    Ok(Pass)
+}
+
 impl kernel::Module for RustSelftests {
     fn init(_name: &'static CStr, _module: &'static ThisModule) -> Result<Self> {
         pr_info!("Rust self tests (init)\n");
 
         do_tests! {
-            test_example // TODO: Remove when there is at least a real test.
+            test_rustfs_symlink,
+            test_rustfs_link,
+            test_rustfs_rename,
+            test_rustfs_dir_nlink
         };
 
         Ok(RustSelftests)